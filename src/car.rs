@@ -0,0 +1,394 @@
+// https://ipld.io/specs/transport/car/carv1/
+// https://ipld.io/specs/transport/car/carv2/
+
+use anyhow::{anyhow, bail, ensure, Result};
+
+use libipld::cbor::DagCborCodec;
+use libipld::cid::Cid;
+use libipld::prelude::*;
+use libipld::{DagCbor, Ipld};
+
+use std::collections::HashSet;
+use std::io::prelude::*;
+use std::io::{Cursor, SeekFrom};
+
+use crate::store::{self, BlockStore};
+
+/// The fixed bytes every CARv2 file starts with.
+const V2_PRAGMA: [u8; 11] = [
+    0x0a, 0xa1, 0x67, 0x76, 0x65, 0x72, 0x73, 0x69, 0x6f, 0x6e, 0x02,
+];
+/// Length of the CARv2 header following the pragma.
+const V2_HEADER_LEN: u64 = 40;
+/// Multicodec code for the `car-index-sorted` index format.
+const INDEX_SORTED: u64 = 0x0400;
+
+/// Refuse to allocate for sections larger than this when reading.
+const MAX_SECTION_LEN: u64 = 32 << 20;
+
+#[derive(Clone, DagCbor, Debug, Eq, PartialEq)]
+struct CarHeader {
+    roots: Vec<Cid>,
+    version: u64,
+}
+
+/// Write the DAG rooted at `root` to `out` as a CARv1, each block once, in
+/// depth-first order.
+pub async fn export_v1<W, S>(store: &S, root: &Cid, out: &mut W) -> Result<()>
+where
+    W: Write + Send,
+    S: BlockStore + ?Sized,
+{
+    write_v1(store, root, out).await?;
+    Ok(())
+}
+
+/// Write the DAG rooted at `root` to `out` as a CARv2 wrapping a CARv1 payload,
+/// followed by a sorted index of the blocks.
+pub async fn export_v2<W, S>(store: &S, root: &Cid, out: &mut W) -> Result<()>
+where
+    W: Write + Seek + Send,
+    S: BlockStore + ?Sized,
+{
+    let start = out.stream_position()?;
+    out.write_all(&V2_PRAGMA)?;
+    out.write_all(&[0; V2_HEADER_LEN as usize])?;
+
+    let data_offset = out.stream_position()? - start;
+    let offsets = write_v1(store, root, out).await?;
+    let index_offset = out.stream_position()? - start;
+    write_index(out, offsets)?;
+
+    // Now the sizes are known, go back and fill in the header.
+    let end = out.stream_position()?;
+    out.seek(SeekFrom::Start(start + V2_PRAGMA.len() as u64))?;
+    out.write_all(&[0; 16])?;
+    out.write_all(&data_offset.to_le_bytes())?;
+    out.write_all(&(index_offset - data_offset).to_le_bytes())?;
+    out.write_all(&index_offset.to_le_bytes())?;
+    out.seek(SeekFrom::Start(end))?;
+    Ok(())
+}
+
+/// Write a CARv1, returning each block's `Cid` and its section's offset from the
+/// start of the CAR.
+async fn write_v1<W, S>(store: &S, root: &Cid, out: &mut W) -> Result<Vec<(Cid, u64)>>
+where
+    W: Write + Send,
+    S: BlockStore + ?Sized,
+{
+    let header = DagCborCodec.encode(&CarHeader {
+        roots: vec![*root],
+        version: 1,
+    })?;
+    let mut pos = write_varint(out, header.len() as u64)?;
+    out.write_all(&header)?;
+    pos += header.len() as u64;
+
+    let mut offsets = Vec::new();
+    let mut seen = HashSet::new();
+    let mut stack = vec![*root];
+    while let Some(cid) = stack.pop() {
+        if !seen.insert(cid) {
+            continue;
+        }
+        let data = store::get_block(store, &cid).await?;
+        let cid_bytes = cid.to_bytes();
+
+        offsets.push((cid, pos));
+        pos += write_varint(out, (cid_bytes.len() + data.len()) as u64)?;
+        out.write_all(&cid_bytes)?;
+        out.write_all(&data)?;
+        pos += (cid_bytes.len() + data.len()) as u64;
+
+        // Push in reverse so children are visited in the order they're linked.
+        let mut links = links(&cid, &data)?;
+        links.reverse();
+        stack.extend(links);
+    }
+    Ok(offsets)
+}
+
+/// Write an `IndexSorted` index of `offsets`, bucketed by digest length.
+fn write_index<W: Write>(out: &mut W, offsets: Vec<(Cid, u64)>) -> Result<()> {
+    let mut buckets: Vec<Vec<(Vec<u8>, u64)>> = Vec::new();
+    for (cid, offset) in offsets {
+        let digest = cid.hash().digest().to_vec();
+        match buckets.iter_mut().find(|b| b[0].0.len() == digest.len()) {
+            Some(bucket) => bucket.push((digest, offset)),
+            None => buckets.push(vec![(digest, offset)]),
+        }
+    }
+    buckets.sort_by_key(|bucket| bucket[0].0.len());
+
+    write_varint(out, INDEX_SORTED)?;
+    out.write_all(&(buckets.len() as i32).to_le_bytes())?;
+    for mut bucket in buckets {
+        bucket.sort();
+        let width = bucket[0].0.len() + 8;
+        out.write_all(&(width as u32).to_le_bytes())?;
+        out.write_all(&((width * bucket.len()) as u64).to_le_bytes())?;
+        for (digest, offset) in bucket {
+            out.write_all(&digest)?;
+            out.write_all(&offset.to_le_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+/// Load every block in the CARv1 or CARv2 `read` into the `store`, verifying that
+/// each block matches its `Cid` and that the DAG under each root is complete.
+/// Returns the roots.
+pub async fn import<R, S>(read: &mut R, store: &S) -> Result<Vec<Cid>>
+where
+    R: Read + Send,
+    S: BlockStore + ?Sized,
+{
+    let mut pragma = [0; V2_PRAGMA.len()];
+    read.read_exact(&mut pragma)?;
+
+    let roots = if pragma == V2_PRAGMA {
+        let mut header = [0; V2_HEADER_LEN as usize];
+        read.read_exact(&mut header)?;
+        let data_offset = u64::from_le_bytes(header[16..24].try_into()?);
+        let data_size = u64::from_le_bytes(header[24..32].try_into()?);
+
+        let skip = data_offset
+            .checked_sub(V2_PRAGMA.len() as u64 + V2_HEADER_LEN)
+            .ok_or_else(|| anyhow!("Invalid CARv2 data offset {}", data_offset))?;
+        std::io::copy(&mut read.by_ref().take(skip), &mut std::io::sink())?;
+        read_v1(&mut read.by_ref().take(data_size), None, store).await?
+    } else {
+        read_v1(read, Some(&pragma), store).await?
+    };
+
+    for root in &roots {
+        check_complete(store, root).await?;
+    }
+    Ok(roots)
+}
+
+/// Read a CARv1 from `read`, the first bytes of which may have already been
+/// consumed into `prefix`.
+async fn read_v1<R, S>(read: &mut R, prefix: Option<&[u8]>, store: &S) -> Result<Vec<Cid>>
+where
+    R: Read + Send,
+    S: BlockStore + ?Sized,
+{
+    let mut read = Cursor::new(prefix.unwrap_or_default().to_vec()).chain(read);
+
+    let header = match read_section(&mut read)? {
+        None => bail!("Empty CAR"),
+        Some(header) => DagCborCodec.decode::<CarHeader>(&header)?,
+    };
+    ensure!(
+        header.version == 1,
+        "Unsupported CAR version {}",
+        header.version
+    );
+
+    while let Some(section) = read_section(&mut read)? {
+        let mut section = Cursor::new(section);
+        let cid = Cid::read_bytes(&mut section)?;
+        let start = section.position() as usize;
+        let data = section.into_inner().split_off(start);
+
+        store::verify_block(&cid, &data)?;
+        store.put(&cid, data.into()).await?;
+    }
+    Ok(header.roots)
+}
+
+/// Read one varint length prefixed section, or `None` at the end of the input.
+fn read_section<R: Read>(read: &mut R) -> Result<Option<Vec<u8>>> {
+    let len = match read_varint(read)? {
+        None => return Ok(None),
+        Some(len) => len,
+    };
+    ensure!(
+        len <= MAX_SECTION_LEN,
+        "CAR section of {} bytes is too large",
+        len
+    );
+
+    let mut section = vec![0; len as usize];
+    read.read_exact(&mut section)?;
+    Ok(Some(section))
+}
+
+/// Walk the DAG rooted at `cid`, failing if any block is missing from the `store`.
+async fn check_complete<S: BlockStore + ?Sized>(store: &S, cid: &Cid) -> Result<()> {
+    let mut seen = HashSet::new();
+    let mut stack = vec![*cid];
+    while let Some(cid) = stack.pop() {
+        if !seen.insert(cid) {
+            continue;
+        }
+        ensure!(store.has(&cid).await?, "CAR is missing block `{}`", cid);
+        stack.extend(links(&cid, &store::get_block(store, &cid).await?)?);
+    }
+    Ok(())
+}
+
+/// The `Cid`s linked to by the block `cid`.
+fn links(cid: &Cid, data: &[u8]) -> Result<Vec<Cid>> {
+    let mut links = Vec::new();
+    match cid.codec() {
+        store::RAW => {}
+        store::DAG_CBOR => DagCborCodec.references::<Ipld, _>(data, &mut links)?,
+        codec => bail!("Unsupported codec {:#x} for `{}`", codec, cid),
+    }
+    Ok(links)
+}
+
+fn write_varint<W: Write>(out: &mut W, mut n: u64) -> Result<u64> {
+    let mut written = 0;
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        written += 1;
+        if n == 0 {
+            out.write_all(&[byte])?;
+            return Ok(written);
+        }
+        out.write_all(&[byte | 0x80])?;
+    }
+}
+
+/// Read an unsigned LEB128 varint, or `None` if the input is already at its end.
+fn read_varint<R: Read>(read: &mut R) -> Result<Option<u64>> {
+    let mut n = 0_u64;
+    for i in 0..10 {
+        let mut byte = [0];
+        if read.read(&mut byte)? == 0 {
+            ensure!(i == 0, "Truncated varint");
+            return Ok(None);
+        }
+        n |= u64::from(byte[0] & 0x7f) << (7 * i);
+        if byte[0] & 0x80 == 0 {
+            return Ok(Some(n));
+        }
+    }
+    bail!("Varint too long")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunker::FixedChunker;
+    use crate::store::{IntegrityError, MemoryBlockStore};
+    use crate::unixfs::{import_file, read_range, Metadata};
+
+    /// A store holding a 5000 byte file split into 5 blocks under its root, and the
+    /// file's contents.
+    async fn file() -> (MemoryBlockStore, Cid, Vec<u8>) {
+        let store = MemoryBlockStore::new();
+        let data = (0..5000).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        let chunker = FixedChunker::new(1000).unwrap();
+        let (_, cid, _) = import_file(&data[..], &chunker, Metadata::default(), 0, &store)
+            .await
+            .unwrap();
+        assert_eq!(store.len(), 6);
+        (store, cid, data)
+    }
+
+    async fn check_import(car: &[u8], root: &Cid, data: &[u8]) {
+        let store = MemoryBlockStore::new();
+        let roots = import(&mut &car[..], &store).await.unwrap();
+        assert_eq!(roots, [*root]);
+        assert_eq!(store.len(), 6);
+        assert_eq!(read_range(&store, root, 0, 5000).await.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn v1_round_trips() {
+        let (store, root, data) = file().await;
+        let mut car = Vec::new();
+        export_v1(&store, &root, &mut car).await.unwrap();
+        check_import(&car, &root, &data).await;
+    }
+
+    #[tokio::test]
+    async fn v2_round_trips_with_an_index() {
+        let (store, root, data) = file().await;
+        let mut car = Cursor::new(Vec::new());
+        export_v2(&store, &root, &mut car).await.unwrap();
+        let car = car.into_inner();
+        check_import(&car, &root, &data).await;
+
+        assert_eq!(car[..V2_PRAGMA.len()], V2_PRAGMA);
+        let header = &car[V2_PRAGMA.len()..][..V2_HEADER_LEN as usize];
+        let field = |i: usize| u64::from_le_bytes(header[i..i + 8].try_into().unwrap());
+        assert_eq!(header[..16], [0; 16]);
+        let (data_offset, data_size, index_offset) = (field(16), field(24), field(32));
+        assert_eq!(data_offset, V2_PRAGMA.len() as u64 + V2_HEADER_LEN);
+        assert_eq!(index_offset, data_offset + data_size);
+
+        // One bucket of sha2-256 digests, each followed by its section's offset.
+        let mut index = Cursor::new(&car[index_offset as usize..]);
+        assert_eq!(read_varint(&mut index).unwrap(), Some(INDEX_SORTED));
+        let mut word = [0; 4];
+        index.read_exact(&mut word).unwrap();
+        assert_eq!(i32::from_le_bytes(word), 1);
+        index.read_exact(&mut word).unwrap();
+        assert_eq!(u32::from_le_bytes(word), 32 + 8);
+        let mut len = [0; 8];
+        index.read_exact(&mut len).unwrap();
+        assert_eq!(u64::from_le_bytes(len), 6 * (32 + 8));
+
+        let payload = &car[data_offset as usize..index_offset as usize];
+        let mut digests = Vec::new();
+        for _ in 0..6 {
+            let mut entry = [0; 32 + 8];
+            index.read_exact(&mut entry).unwrap();
+            let (digest, offset) = entry.split_at(32);
+            let offset = u64::from_le_bytes(offset.try_into().unwrap());
+            let mut section = Cursor::new(&payload[offset as usize..]);
+            read_varint(&mut section).unwrap();
+            let cid = Cid::read_bytes(&mut section).unwrap();
+            assert_eq!(cid.hash().digest(), digest);
+            digests.push(digest.to_vec());
+        }
+        assert!(digests.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(index.position() as usize, car.len() - index_offset as usize);
+    }
+
+    #[tokio::test]
+    async fn import_rejects_a_tampered_block() {
+        let (store, root, _) = file().await;
+        let mut car = Vec::new();
+        export_v1(&store, &root, &mut car).await.unwrap();
+        // The last bytes belong to the data of the last block.
+        *car.last_mut().unwrap() ^= 1;
+
+        let err = import(&mut &car[..], &MemoryBlockStore::new())
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<IntegrityError>().is_some(), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn import_rejects_a_missing_block() {
+        let (store, root, _) = file().await;
+        // A CAR holding just the root, without the blocks it links to.
+        let header = DagCborCodec
+            .encode(&CarHeader {
+                roots: vec![root],
+                version: 1,
+            })
+            .unwrap();
+        let block = store.get(&root).await.unwrap();
+        let cid = root.to_bytes();
+        let mut car = Vec::new();
+        write_varint(&mut car, header.len() as u64).unwrap();
+        car.extend_from_slice(&header);
+        write_varint(&mut car, (cid.len() + block.len()) as u64).unwrap();
+        car.extend_from_slice(&cid);
+        car.extend_from_slice(&block);
+
+        let err = import(&mut &car[..], &MemoryBlockStore::new())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("missing block"), "{}", err);
+    }
+}
@@ -0,0 +1,392 @@
+use anyhow::{anyhow, bail, ensure, Result};
+
+use std::fmt::{self, Display};
+use std::io::prelude::*;
+
+/// The default chunker, `fixed:262144`.
+pub const DEFAULT: &str = "fixed:262144";
+
+/// Decides where to split a stream of bytes into blocks.
+///
+/// Every chunker is identified by a spec string, which is what its `Display`
+/// impl produces and what [`parse`](parse) accepts. The spec is recorded on each
+/// imported file so that later versions can be chunked the same way and share
+/// as many blocks as possible.
+pub trait Chunker: Display + Send + Sync {
+    /// The largest chunk this chunker will produce.
+    fn max_size(&self) -> usize;
+
+    /// The length of the first chunk of `data`. `data` holds at least
+    /// [`max_size`](Chunker::max_size) bytes unless the end of the input has
+    /// been reached, and is never empty.
+    fn cut(&self, data: &[u8]) -> usize;
+}
+
+/// Parse a chunker spec, one of:
+///
+/// - `fixed:<size>`
+/// - `rabin:<min>:<avg>:<max>`
+/// - `fastcdc:<min>:<avg>:<max>`
+///
+/// Sizes are in bytes, with an optional `k`, `m` or `g` suffix.
+pub fn parse(spec: &str) -> Result<Box<dyn Chunker>> {
+    let mut parts = spec.split(':');
+    let name = parts.next().unwrap_or_default();
+    let sizes = parts.map(parse_size).collect::<Result<Vec<usize>>>()?;
+    match (name, sizes.as_slice()) {
+        ("fixed", &[size]) => Ok(Box::new(FixedChunker::new(size)?)),
+        ("rabin", &[min, avg, max]) => Ok(Box::new(RabinChunker::new(min, avg, max)?)),
+        ("fastcdc", &[min, avg, max]) => Ok(Box::new(FastCdcChunker::new(min, avg, max)?)),
+        _ => bail!("Invalid chunker `{}`", spec),
+    }
+}
+
+fn parse_size(s: &str) -> Result<usize> {
+    let (digits, scale) = match s.to_ascii_lowercase().chars().last() {
+        Some('k') => (&s[..s.len() - 1], 1 << 10),
+        Some('m') => (&s[..s.len() - 1], 1 << 20),
+        Some('g') => (&s[..s.len() - 1], 1 << 30),
+        _ => (s, 1),
+    };
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(scale))
+        .ok_or_else(|| anyhow!("Invalid size `{}`", s))
+}
+
+fn check_sizes(min: usize, avg: usize, max: usize) -> Result<()> {
+    ensure!(
+        0 < min && min <= avg && avg <= max,
+        "Chunk sizes must satisfy 0 < min <= avg <= max, got {}:{}:{}",
+        min,
+        avg,
+        max
+    );
+    Ok(())
+}
+
+/// Splits the input into chunks of exactly `size` bytes, apart from the last.
+pub struct FixedChunker {
+    size: usize,
+}
+
+impl FixedChunker {
+    pub fn new(size: usize) -> Result<Self> {
+        ensure!(size > 0, "Chunk size must be positive");
+        Ok(FixedChunker { size })
+    }
+}
+
+impl Display for FixedChunker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "fixed:{}", self.size)
+    }
+}
+
+impl Chunker for FixedChunker {
+    fn max_size(&self) -> usize {
+        self.size
+    }
+
+    fn cut(&self, data: &[u8]) -> usize {
+        data.len().min(self.size)
+    }
+}
+
+/// Width of the sliding window hashed by [`RabinChunker`](RabinChunker).
+const RABIN_WINDOW: usize = 48;
+const RABIN_PRIME: u64 = 0x3DA3358B4DC173;
+
+/// Content defined chunking using a Rabin-Karp rolling hash over a
+/// [`RABIN_WINDOW`](RABIN_WINDOW) byte window, cutting wherever the low bits of
+/// the hash are all zero.
+pub struct RabinChunker {
+    min: usize,
+    avg: usize,
+    max: usize,
+    mask: u64,
+    /// `RABIN_PRIME ^ RABIN_WINDOW`, for removing the byte leaving the window.
+    out_factor: u64,
+}
+
+impl RabinChunker {
+    pub fn new(min: usize, avg: usize, max: usize) -> Result<Self> {
+        check_sizes(min, avg, max)?;
+        ensure!(
+            min >= RABIN_WINDOW,
+            "Minimum chunk size must be at least {}",
+            RABIN_WINDOW
+        );
+        Ok(RabinChunker {
+            min,
+            avg,
+            max,
+            mask: mask_for(avg - min + 1),
+            out_factor: (0..RABIN_WINDOW).fold(1, |acc: u64, _| acc.wrapping_mul(RABIN_PRIME)),
+        })
+    }
+}
+
+impl Display for RabinChunker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "rabin:{}:{}:{}", self.min, self.avg, self.max)
+    }
+}
+
+impl Chunker for RabinChunker {
+    fn max_size(&self) -> usize {
+        self.max
+    }
+
+    fn cut(&self, data: &[u8]) -> usize {
+        let end = data.len().min(self.max);
+        if end <= self.min {
+            return end;
+        }
+
+        let mut hash = 0_u64;
+        for i in (self.min - RABIN_WINDOW)..end {
+            hash = hash
+                .wrapping_mul(RABIN_PRIME)
+                .wrapping_add(u64::from(data[i]) + 1);
+            if i >= self.min {
+                hash = hash.wrapping_sub(
+                    self.out_factor
+                        .wrapping_mul(u64::from(data[i - RABIN_WINDOW]) + 1),
+                );
+                if hash & self.mask == 0 {
+                    return i + 1;
+                }
+            }
+        }
+        end
+    }
+}
+
+/// Content defined chunking as described in "FastCDC: a Fast and Efficient
+/// Content-Defined Chunking Approach for Data Deduplication" (Xia et al. 2016),
+/// using a gear hash and normalized chunking around the average size.
+pub struct FastCdcChunker {
+    min: usize,
+    avg: usize,
+    max: usize,
+    /// Harder to satisfy mask, used before the average size.
+    mask_s: u64,
+    /// Easier to satisfy mask, used after the average size.
+    mask_l: u64,
+}
+
+impl FastCdcChunker {
+    pub fn new(min: usize, avg: usize, max: usize) -> Result<Self> {
+        check_sizes(min, avg, max)?;
+        let bits = mask_for(avg).count_ones();
+        Ok(FastCdcChunker {
+            min,
+            avg,
+            max,
+            mask_s: high_bits((bits + 2).min(63)),
+            mask_l: high_bits(bits.saturating_sub(2).max(1)),
+        })
+    }
+}
+
+impl Display for FastCdcChunker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "fastcdc:{}:{}:{}", self.min, self.avg, self.max)
+    }
+}
+
+impl Chunker for FastCdcChunker {
+    fn max_size(&self) -> usize {
+        self.max
+    }
+
+    fn cut(&self, data: &[u8]) -> usize {
+        let end = data.len().min(self.max);
+        if end <= self.min {
+            return end;
+        }
+
+        let normal = self.avg.min(end);
+        let mut hash = 0_u64;
+        for (i, b) in data.iter().enumerate().take(end).skip(self.min) {
+            hash = (hash << 1).wrapping_add(GEAR[usize::from(*b)]);
+            let mask = if i < normal { self.mask_s } else { self.mask_l };
+            if hash & mask == 0 {
+                return i + 1;
+            }
+        }
+        end
+    }
+}
+
+/// A mask of the low `log2(size)` bits, so that a uniformly distributed hash
+/// matches it about once every `size` bytes.
+fn mask_for(size: usize) -> u64 {
+    let bits = usize::BITS - size.max(2).leading_zeros() - 1;
+    (1 << bits) - 1
+}
+
+fn high_bits(n: u32) -> u64 {
+    !0_u64 << (64 - n)
+}
+
+/// Random values for the gear hash, generated with splitmix64.
+static GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0_u64; 256];
+    let mut state = 0x7470_6f73_u64;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// An iterator over the chunks of `read`, as split by a [`Chunker`](Chunker).
+pub struct Chunks<'c, R> {
+    chunker: &'c dyn Chunker,
+    read: R,
+    buf: Vec<u8>,
+    eof: bool,
+}
+
+impl<'c, R: Read> Chunks<'c, R> {
+    pub fn new(chunker: &'c dyn Chunker, read: R) -> Self {
+        Chunks {
+            chunker,
+            read,
+            buf: Vec::new(),
+            eof: false,
+        }
+    }
+}
+
+impl<'c, R: Read> Iterator for Chunks<'c, R> {
+    type Item = std::io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let max = self.chunker.max_size();
+        if !self.eof && self.buf.len() < max {
+            let want = (max - self.buf.len()) as u64;
+            match (&mut self.read).take(want).read_to_end(&mut self.buf) {
+                Err(e) => return Some(Err(e)),
+                Ok(n) => self.eof = (n as u64) < want,
+            }
+        }
+
+        if self.buf.is_empty() {
+            return None;
+        }
+
+        let n = self.chunker.cut(&self.buf).max(1);
+        let rest = self.buf.split_off(n);
+        Some(Ok(std::mem::replace(&mut self.buf, rest)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashSet;
+
+    const SPECS: [&str; 3] = [
+        "fixed:4096",
+        "rabin:1024:4096:16384",
+        "fastcdc:1024:4096:16384",
+    ];
+
+    /// Bytes that look random, so content defined chunkers cut at their usual rate.
+    fn data(len: usize) -> Vec<u8> {
+        let mut state = 1_u64;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn chunks(chunker: &dyn Chunker, data: &[u8]) -> Vec<Vec<u8>> {
+        Chunks::new(chunker, data).map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn parse_round_trips() {
+        for spec in SPECS {
+            assert_eq!(parse(spec).unwrap().to_string(), spec);
+        }
+        assert_eq!(parse("fastcdc:1k:4k:16k").unwrap().to_string(), SPECS[2]);
+        for spec in [
+            "fixed",
+            "fixed:0",
+            "rabin:16:32:64",
+            "fastcdc:2:1:3",
+            "cdc:1:2:3",
+        ] {
+            assert!(parse(spec).is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn chunk_sizes_are_within_bounds() {
+        let data = data(1 << 20);
+        for spec in SPECS {
+            let chunker = parse(spec).unwrap();
+            let chunks = chunks(&*chunker, &data);
+            assert_eq!(chunks.concat(), data);
+
+            let (_, rest) = chunks.split_last().unwrap();
+            let min = if spec.starts_with("fixed") {
+                4096
+            } else {
+                1024
+            };
+            for chunk in rest {
+                assert!(
+                    min <= chunk.len() && chunk.len() <= chunker.max_size(),
+                    "{}",
+                    spec
+                );
+            }
+            // The average is only a target, so allow some slack either way.
+            let avg = data.len() / chunks.len();
+            assert!((2048..=8192).contains(&avg), "{} averaged {}", spec, avg);
+        }
+    }
+
+    #[test]
+    fn content_defined_chunkers_resync_after_an_insert() {
+        let data = data(1 << 20);
+        let mut inserted = data.clone();
+        inserted.insert(100, 0xff);
+        for spec in &SPECS[1..] {
+            let chunker = parse(spec).unwrap();
+            let before = chunks(&*chunker, &data);
+            let after = chunks(&*chunker, &inserted)
+                .into_iter()
+                .collect::<HashSet<_>>();
+            let changed = before
+                .iter()
+                .filter(|chunk| !after.contains(*chunk))
+                .count();
+            assert!(
+                changed <= 2,
+                "{} changed {} of {} chunks",
+                spec,
+                changed,
+                before.len()
+            );
+        }
+    }
+}
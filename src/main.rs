@@ -1,30 +1,160 @@
-use clap::{crate_authors, crate_description, crate_name, crate_version, App, Arg, SubCommand};
+use clap::{
+    crate_authors, crate_description, crate_name, crate_version, App, Arg, ArgMatches, SubCommand,
+};
 
-use hyper::client::HttpConnector;
-
-use ipfs_api_backend_hyper::{IpfsApi, IpfsClient};
+use futures::io::{AllowStdIo, AsyncSeekExt};
 
 use libipld::link;
 
 use std::fs;
+use std::io;
 use std::io::prelude::*;
-use std::io::stdin;
+use std::io::{stdin, stdout, SeekFrom};
 use std::path::Path;
+use std::sync::Arc;
 
+mod car;
+mod chunker;
+mod store;
 mod unixfs;
 
+use store::BlockStore;
+use unixfs::Metadata;
+
 #[tokio::main]
 async fn main() {
     let app = App::new(crate_name!())
         .version(crate_version!())
         .author(crate_authors!())
         .about(crate_description!())
-        .subcommand(SubCommand::with_name("add").arg(Arg::with_name("input").index(1)))
-        .subcommand(SubCommand::with_name("get").arg(Arg::with_name("id").index(1).required(true)))
+        .arg(
+            Arg::with_name("store")
+                .long("store")
+                .takes_value(true)
+                .global(true)
+                .help("Block store to use: `ipfs`, `path:<dir>` or `memory` [default: ipfs]"),
+        )
+        .subcommand(
+            SubCommand::with_name("add")
+                .arg(Arg::with_name("input").index(1))
+                .arg(
+                    Arg::with_name("recursive")
+                        .short("r")
+                        .long("recursive")
+                        .requires("input")
+                        .help("Import the directory tree rooted at input"),
+                )
+                .arg(
+                    Arg::with_name("tar")
+                        .long("tar")
+                        .conflicts_with("recursive")
+                        .help("Import input as a tar archive"),
+                )
+                .arg(
+                    Arg::with_name("dereference")
+                        .short("L")
+                        .long("dereference")
+                        .conflicts_with("tar")
+                        .help("Import what symbolic links point to, instead of the links"),
+                )
+                .args(&xattr_filter_args())
+                .arg(
+                    Arg::with_name("chunker")
+                        .long("chunker")
+                        .takes_value(true)
+                        .default_value(chunker::DEFAULT)
+                        .help("How to split files into blocks, e.g. `fastcdc:16k:64k:256k`"),
+                )
+                .arg(inline_limit_arg())
+                .arg(
+                    Arg::with_name("stats")
+                        .long("stats")
+                        .help("Print deduplication statistics to stderr"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("get")
+                .arg(Arg::with_name("id").index(1).required(true))
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("tar")
+                        .long("tar")
+                        .help("Write the tree as a tar archive, to output or stdout"),
+                )
+                .arg(
+                    Arg::with_name("no-owner")
+                        .long("no-owner")
+                        .help("Don't restore file ownership, for unprivileged users"),
+                )
+                .arg(
+                    Arg::with_name("hardlinks")
+                        .long("hardlinks")
+                        .help("Recreate files imported as hard links of each other as hard links"),
+                )
+                .args(&xattr_filter_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("cat")
+                .arg(Arg::with_name("id").index(1).required(true))
+                .arg(
+                    Arg::with_name("offset")
+                        .long("offset")
+                        .takes_value(true)
+                        .default_value("0")
+                        .help("Byte offset to start reading at"),
+                )
+                .arg(
+                    Arg::with_name("length")
+                        .long("length")
+                        .takes_value(true)
+                        .help("Number of bytes to read [default: to the end of the file]"),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("update")
                 .arg(Arg::with_name("id").index(1).required(true))
-                .arg(Arg::with_name("input").index(2)),
+                .arg(Arg::with_name("input").index(2))
+                .arg(
+                    Arg::with_name("author")
+                        .long("author")
+                        .takes_value(true)
+                        .help("Author to record on the new version"),
+                )
+                .arg(inline_limit_arg()),
+        )
+        .subcommand(SubCommand::with_name("log").arg(Arg::with_name("id").index(1).required(true)))
+        .subcommand(
+            SubCommand::with_name("export")
+                .arg(Arg::with_name("id").index(1).required(true))
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("v2")
+                        .long("v2")
+                        .help("Write a CARv2 with an index instead of a CARv1"),
+                ),
+        )
+        .subcommand(SubCommand::with_name("import").arg(Arg::with_name("input").index(1)))
+        .subcommand(
+            SubCommand::with_name("merge")
+                .arg(Arg::with_name("base").index(1).required(true))
+                .arg(Arg::with_name("ours").index(2).required(true))
+                .arg(Arg::with_name("theirs").index(3).required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("diff")
+                .arg(Arg::with_name("a").index(1).required(true))
+                .arg(Arg::with_name("b").index(2).required(true)),
         )
         .subcommand(SubCommand::with_name("test"));
 
@@ -32,31 +162,272 @@ async fn main() {
 
     match matches.subcommand() {
         ("add", Some(add_matches)) => {
-            let mut f = path_or_stdin(add_matches.value_of("input"));
-            match unixfs::import_file(&mut f, IpfsClient::<HttpConnector>::default()).await {
+            let store = open_store(add_matches.value_of("store"));
+            let chunker = match chunker::parse(add_matches.value_of("chunker").unwrap()) {
+                Err(e) => panic!("{}", e),
+                Ok(chunker) => chunker,
+            };
+            let opts = unixfs::ImportOptions {
+                dereference: add_matches.is_present("dereference"),
+                xattrs: xattr_filter(add_matches),
+                inline_limit: inline_limit(add_matches),
+            };
+            let is_symlink = add_matches
+                .value_of("input")
+                .and_then(|path| fs::symlink_metadata(path).ok())
+                .is_some_and(|meta| meta.file_type().is_symlink());
+            let res = if add_matches.is_present("recursive") {
+                let path = Path::new(add_matches.value_of("input").unwrap());
+                unixfs::import_dir(path, &*chunker, &opts, &*store).await
+            } else if add_matches.is_present("tar") {
+                let f = path_or_stdin(add_matches.value_of("input"));
+                unixfs::archive::import_tar(f, &*chunker, opts.inline_limit, &*store).await
+            } else if is_symlink && !opts.dereference {
+                let path = Path::new(add_matches.value_of("input").unwrap());
+                unixfs::import_symlink(path, &opts.xattrs, &*store)
+                    .await
+                    .map(|cid| (cid, Default::default()))
+            } else if let Some(path) = add_matches.value_of("input") {
+                let path = Path::new(path);
+                let meta = match Metadata::from_path(path, true, &opts.xattrs) {
+                    Err(e) => panic!("{}", e),
+                    Ok(meta) => meta,
+                };
+                // Only regular files can have holes to look for.
+                match fs::File::open(path) {
+                    Err(why) => panic!("couldn't open {}: {}", path.display(), why),
+                    Ok(f) if f.metadata().is_ok_and(|m| m.is_file()) => {
+                        unixfs::import_sparse_file(&f, &*chunker, meta, opts.inline_limit, &*store)
+                            .await
+                    }
+                    Ok(f) => {
+                        unixfs::import_file(f, &*chunker, meta, opts.inline_limit, &*store).await
+                    }
+                }
+                .map(|(_file, cid, stats)| (cid, stats))
+            } else {
+                unixfs::import_file(
+                    stdin(),
+                    &*chunker,
+                    Metadata::default(),
+                    opts.inline_limit,
+                    &*store,
+                )
+                .await
+                .map(|(_file, cid, stats)| (cid, stats))
+            };
+            match res {
                 Err(e) => {
                     panic!("{}", e);
                 }
-                Ok((_file, cid)) => {
+                Ok((cid, stats)) => {
                     print!("{}", cid);
+                    if add_matches.is_present("stats") {
+                        eprintln!("{}", stats);
+                    }
                 }
             };
         }
-        // ("get", Some(get_matches)) => {
-        //     let id = get_matches.value_of("id").unwrap();
-
-        //     let client = IpfsClient::<HttpConnector>::default();
-        //     let mut fr = unixfs::FileReader::new(parse_cid(id).unwrap(), client);
-
-        //     let mut s = String::new();
-        //     if let Err(e) = Pin::new(&mut fr).read_to_string(&mut s).await {
-        //         panic!("{}", e);
-        //     }
-        //     println!("{}", s);
-        // }
+        ("get", Some(get_matches)) => {
+            let id = get_matches.value_of("id").unwrap();
+            let cid = match parse_cid(id) {
+                Err(e) => panic!("invalid cid `{}`: {}", id, e),
+                Ok(cid) => cid,
+            };
+
+            let store = open_store(get_matches.value_of("store"));
+            if get_matches.is_present("tar") {
+                let mut out: Box<dyn Write + Send> = match get_matches.value_of("output") {
+                    None => Box::new(io::BufWriter::new(stdout())),
+                    Some(path) => match fs::File::create(path) {
+                        Err(why) => panic!("couldn't create {}: {}", path, why),
+                        Ok(file) => Box::new(io::BufWriter::new(file)),
+                    },
+                };
+                let res = unixfs::archive::export_tar(&store, &cid, &mut out).await;
+                if let Err(e) = res.and_then(|_| Ok(out.flush()?)) {
+                    panic!("{}", e);
+                }
+                return;
+            }
+            match get_matches.value_of("output") {
+                Some(path) => {
+                    let opts = unixfs::ExportOptions {
+                        owner: !get_matches.is_present("no-owner"),
+                        xattrs: xattr_filter(get_matches),
+                        hardlinks: get_matches.is_present("hardlinks"),
+                    };
+                    if let Err(e) = unixfs::export(&store, &cid, Path::new(path), &opts).await {
+                        panic!("{}", e);
+                    }
+                }
+                None => {
+                    let mut fr = unixfs::FileReader::new(cid, store);
+                    let mut out = AllowStdIo::new(stdout());
+                    if let Err(e) = futures::io::copy(&mut fr, &mut out).await {
+                        panic!("{}", e);
+                    }
+                }
+            }
+        }
+        ("cat", Some(cat_matches)) => {
+            let id = cat_matches.value_of("id").unwrap();
+            let cid = match parse_cid(id) {
+                Err(e) => panic!("invalid cid `{}`: {}", id, e),
+                Ok(cid) => cid,
+            };
+            let offset = parse_u64(cat_matches.value_of("offset").unwrap());
+
+            let store = open_store(cat_matches.value_of("store"));
+            match cat_matches.value_of("length").map(parse_u64) {
+                Some(length) => match unixfs::read_range(&*store, &cid, offset, length).await {
+                    Err(e) => panic!("{}", e),
+                    Ok(data) => {
+                        if let Err(e) = stdout().write_all(&data) {
+                            panic!("{}", e);
+                        }
+                    }
+                },
+                None => {
+                    let mut fr = unixfs::FileReader::new(cid, store);
+                    let mut out = AllowStdIo::new(stdout());
+                    let res = match fr.seek(SeekFrom::Start(offset)).await {
+                        Err(e) => Err(e),
+                        Ok(_) => futures::io::copy(&mut fr, &mut out).await,
+                    };
+                    if let Err(e) = res {
+                        panic!("{}", e);
+                    }
+                }
+            }
+        }
         ("update", Some(update_matches)) => {
-            let _id = update_matches.value_of("input").unwrap();
-            let _f = path_or_stdin(update_matches.value_of("input"));
+            let id = update_matches.value_of("id").unwrap();
+            let cid = match parse_cid(id) {
+                Err(e) => panic!("invalid cid `{}`: {}", id, e),
+                Ok(cid) => cid,
+            };
+            let mut f = path_or_stdin(update_matches.value_of("input"));
+
+            let store = open_store(update_matches.value_of("store"));
+            let author = update_matches.value_of("author").map(str::to_string);
+            let inline_limit = inline_limit(update_matches);
+            match unixfs::update_file(&cid, &mut f, author, inline_limit, &*store).await {
+                Err(e) => {
+                    panic!("{}", e);
+                }
+                Ok((_file, cid, reused, chunks)) => {
+                    print!("{}", cid);
+                    eprintln!("reused {} of {} chunks", reused, chunks);
+                }
+            }
+        }
+        ("log", Some(log_matches)) => {
+            let id = log_matches.value_of("id").unwrap();
+            let cid = match parse_cid(id) {
+                Err(e) => panic!("invalid cid `{}`: {}", id, e),
+                Ok(cid) => cid,
+            };
+
+            let store = open_store(log_matches.value_of("store"));
+            match unixfs::history(&*store, &cid).await {
+                Err(e) => panic!("{}", e),
+                Ok(versions) => {
+                    for (cid, file) in versions {
+                        let time = file
+                            .timestamp()
+                            .map_or_else(|| "-".to_string(), |t| t.to_string());
+                        print!("{}\t{}\t{}", cid, file.size(), time);
+                        if let Some(author) = file.author() {
+                            print!("\t{}", author);
+                        }
+                        println!();
+                    }
+                }
+            }
+        }
+        ("diff", Some(diff_matches)) => {
+            let mut cids = ["a", "b"].iter().map(|arg| {
+                let id = diff_matches.value_of(arg).unwrap();
+                match parse_cid(id) {
+                    Err(e) => panic!("invalid cid `{}`: {}", id, e),
+                    Ok(cid) => cid,
+                }
+            });
+            let (a, b) = (cids.next().unwrap(), cids.next().unwrap());
+
+            let store = open_store(diff_matches.value_of("store"));
+            match unixfs::diff::diff(&*store, &a, &b).await {
+                Err(e) => panic!("{}", e),
+                Ok(changes) => {
+                    for change in changes {
+                        println!("{}", change);
+                    }
+                }
+            }
+        }
+        ("merge", Some(merge_matches)) => {
+            let mut cids = ["base", "ours", "theirs"].iter().map(|arg| {
+                let id = merge_matches.value_of(arg).unwrap();
+                match parse_cid(id) {
+                    Err(e) => panic!("invalid cid `{}`: {}", id, e),
+                    Ok(cid) => cid,
+                }
+            });
+            let (base, ours, theirs) = (
+                cids.next().unwrap(),
+                cids.next().unwrap(),
+                cids.next().unwrap(),
+            );
+
+            let store = open_store(merge_matches.value_of("store"));
+            match unixfs::merge::merge(&*store, &base, &ours, &theirs).await {
+                Err(e) => panic!("{}", e),
+                Ok((root, conflicts)) => {
+                    let conflicts = conflicts.iter().map(|c| c.to_json()).collect::<Vec<_>>();
+                    println!(
+                        "{}",
+                        serde_json::json!({ "root": root.to_string(), "conflicts": conflicts })
+                    );
+                    if !conflicts.is_empty() {
+                        std::process::exit(1);
+                    }
+                }
+            }
+        }
+        ("export", Some(export_matches)) => {
+            let id = export_matches.value_of("id").unwrap();
+            let cid = match parse_cid(id) {
+                Err(e) => panic!("invalid cid `{}`: {}", id, e),
+                Ok(cid) => cid,
+            };
+            let path = Path::new(export_matches.value_of("output").unwrap());
+            let mut out = match fs::File::create(path) {
+                Err(why) => panic!("couldn't create {}: {}", path.display(), why),
+                Ok(file) => io::BufWriter::new(file),
+            };
+
+            let store = open_store(export_matches.value_of("store"));
+            let res = if export_matches.is_present("v2") {
+                car::export_v2(&*store, &cid, &mut out).await
+            } else {
+                car::export_v1(&*store, &cid, &mut out).await
+            };
+            if let Err(e) = res.and_then(|_| Ok(out.flush()?)) {
+                panic!("{}", e);
+            }
+        }
+        ("import", Some(import_matches)) => {
+            let mut f = path_or_stdin(import_matches.value_of("input"));
+            let store = open_store(import_matches.value_of("store"));
+            match car::import(&mut f, &*store).await {
+                Err(e) => panic!("{}", e),
+                Ok(roots) => {
+                    for root in roots {
+                        println!("{}", root);
+                    }
+                }
+            }
         }
         _ => {
             println!("{}", matches.usage());
@@ -64,6 +435,13 @@ async fn main() {
     }
 }
 
+fn open_store(spec: Option<&str>) -> Arc<dyn BlockStore> {
+    match store::open(spec.unwrap_or("ipfs")) {
+        Err(e) => panic!("{}", e),
+        Ok(store) => store,
+    }
+}
+
 fn path_or_stdin(path: Option<&str>) -> Box<dyn Read + Send + Sync> {
     match path {
         Some(path) => {
@@ -77,6 +455,60 @@ fn path_or_stdin(path: Option<&str>) -> Box<dyn Read + Send + Sync> {
     }
 }
 
+fn xattr_filter_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("xattr-include")
+            .long("xattr-include")
+            .takes_value(true)
+            .multiple(true)
+            .require_delimiter(true)
+            .help("Only keep extended attributes in these namespaces, e.g. `user`"),
+        Arg::with_name("xattr-exclude")
+            .long("xattr-exclude")
+            .takes_value(true)
+            .multiple(true)
+            .require_delimiter(true)
+            .help("Skip extended attributes in these namespaces, e.g. `security`"),
+    ]
+}
+
+fn xattr_filter(matches: &ArgMatches) -> unixfs::XattrFilter {
+    let namespaces = |name: &str| {
+        matches
+            .values_of(name)
+            .into_iter()
+            .flatten()
+            .map(str::to_string)
+            .collect()
+    };
+    unixfs::XattrFilter {
+        include: namespaces("xattr-include"),
+        exclude: namespaces("xattr-exclude"),
+    }
+}
+
+fn inline_limit_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("inline-limit")
+        .long("inline-limit")
+        .takes_value(true)
+        .help("Store files up to this many bytes inside their node [default: 1024]")
+}
+
+fn inline_limit(matches: &ArgMatches) -> usize {
+    matches
+        .value_of("inline-limit")
+        .map_or(unixfs::DEFAULT_INLINE_LIMIT, |s| {
+            parse_u64(s).try_into().unwrap_or(usize::MAX)
+        })
+}
+
+fn parse_u64(s: &str) -> u64 {
+    match s.parse() {
+        Err(e) => panic!("invalid number `{}`: {}", s, e),
+        Ok(n) => n,
+    }
+}
+
 fn parse_cid(s: &str) -> Result<cid::Cid, cid::Error> {
     let (_, bytes) = multibase::decode(s)?;
     cid::Cid::read_bytes(std::io::Cursor::new(bytes))
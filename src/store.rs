@@ -0,0 +1,163 @@
+use anyhow::{anyhow, bail, Result};
+
+use async_trait::async_trait;
+
+use bytes::Bytes;
+
+use hyper::client::HttpConnector;
+
+use ipfs_api_backend_hyper::IpfsClient;
+
+use libipld::cbor::DagCborCodec;
+use libipld::cid::Cid;
+use libipld::multihash::{Code, MultihashDigest};
+use libipld::prelude::*;
+
+use std::sync::Arc;
+
+use thiserror::Error;
+
+mod flatfs;
+mod ipfs;
+mod memory;
+
+pub use flatfs::FlatFsBlockStore;
+pub use ipfs::IpfsBlockStore;
+pub use memory::MemoryBlockStore;
+
+/// Multicodec code for raw binary blocks.
+pub const RAW: u64 = 0x55;
+/// Multicodec code for DAG-CBOR blocks.
+pub const DAG_CBOR: u64 = 0x71;
+
+/// A content addressed store of blocks, keyed by their [`Cid`](Cid).
+///
+/// Implementations don't check that blocks hash to their `Cid` in either
+/// direction. Callers should compute the `Cid` of new data with [`cid_for`](cid_for),
+/// or use [`put_node`](put_node), and read blocks with [`get_block`](get_block) or
+/// [`get_node`](get_node), which verify them.
+#[async_trait]
+pub trait BlockStore: Send + Sync {
+    /// Store `data` under `cid`. Storing a block that already exists is not an error.
+    async fn put(&self, cid: &Cid, data: Bytes) -> Result<()>;
+
+    /// Fetch the block `cid`, failing if it is not present. The data is returned as
+    /// stored, without checking it against `cid`.
+    async fn get(&self, cid: &Cid) -> Result<Bytes>;
+
+    /// Whether the block `cid` is present in the store.
+    async fn has(&self, cid: &Cid) -> Result<bool>;
+
+    /// Remove the block `cid`. Removing a block that doesn't exist is not an error.
+    // Nothing in the CLI removes blocks yet.
+    #[cfg_attr(not(test), allow(dead_code))]
+    async fn delete(&self, cid: &Cid) -> Result<()>;
+}
+
+/// Open the block store described by `spec`, which is one of:
+///
+/// - `ipfs`: the block API of the local ipfs daemon.
+/// - `path:<dir>`: a [`FlatFsBlockStore`](FlatFsBlockStore) rooted at `<dir>`.
+/// - `memory`: a [`MemoryBlockStore`](MemoryBlockStore) that is dropped on exit, to
+///   see what an import would produce without storing anything.
+pub fn open(spec: &str) -> Result<Arc<dyn BlockStore>> {
+    match spec.split_once(':') {
+        None if spec == "ipfs" => Ok(Arc::new(IpfsBlockStore::new(
+            IpfsClient::<HttpConnector>::default(),
+        ))),
+        None if spec == "memory" => Ok(Arc::new(MemoryBlockStore::new())),
+        Some(("path", dir)) if !dir.is_empty() => Ok(Arc::new(FlatFsBlockStore::new(dir))),
+        _ => bail!("Invalid block store `{}`", spec),
+    }
+}
+
+/// Compute the CIDv1 of `data` encoded with `codec`, using sha2-256.
+pub fn cid_for(codec: u64, data: &[u8]) -> Cid {
+    Cid::new_v1(codec, Code::Sha2_256.digest(data))
+}
+
+/// A block whose data doesn't hash to the multihash in its `Cid`.
+#[derive(Debug, Error)]
+#[error("Block `{cid}` does not match its hash")]
+pub struct IntegrityError {
+    pub cid: Cid,
+}
+
+/// Check that `data` hashes to the multihash in `cid`, with whichever hash
+/// function `cid` names, failing with an [`IntegrityError`](IntegrityError) if not.
+pub fn verify_block(cid: &Cid, data: &[u8]) -> Result<()> {
+    let code = Code::try_from(cid.hash().code()).map_err(|_| {
+        anyhow!(
+            "Block `{}` uses unsupported hash function 0x{:x}",
+            cid,
+            cid.hash().code()
+        )
+    })?;
+    if code.digest(data) != *cid.hash() {
+        return Err(IntegrityError { cid: *cid }.into());
+    }
+    Ok(())
+}
+
+/// Fetch the block `cid` from the `store`, failing with an
+/// [`IntegrityError`](IntegrityError) if its data doesn't match `cid`.
+pub async fn get_block<S>(store: &S, cid: &Cid) -> Result<Bytes>
+where
+    S: BlockStore + ?Sized,
+{
+    let data = store.get(cid).await?;
+    verify_block(cid, &data)?;
+    Ok(data)
+}
+
+/// The `Cid` that [`put_node`](put_node) would store `node` under.
+pub fn node_cid<T>(node: &T) -> Result<Cid>
+where
+    T: Encode<DagCborCodec> + ?Sized,
+{
+    Ok(cid_for(DAG_CBOR, &DagCborCodec.encode(node)?))
+}
+
+/// Encode `node` as DAG-CBOR and store it, returning its `Cid`.
+pub async fn put_node<S, T>(store: &S, node: &T) -> Result<Cid>
+where
+    S: BlockStore + ?Sized,
+    T: Encode<DagCborCodec> + ?Sized,
+{
+    let data = DagCborCodec.encode(node)?;
+    let cid = cid_for(DAG_CBOR, &data);
+    store.put(&cid, data.into()).await?;
+    Ok(cid)
+}
+
+/// Fetch the DAG-CBOR block `cid` with [`get_block`](get_block) and decode it.
+pub async fn get_node<S, T>(store: &S, cid: &Cid) -> Result<T>
+where
+    S: BlockStore + ?Sized,
+    T: Decode<DagCborCodec>,
+{
+    let data = get_block(store, cid).await?;
+    DagCborCodec
+        .decode::<T>(&data)
+        .map_err(|e| anyhow!("Error decoding `{}`: {}", cid, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn get_block_rejects_corrupt_data() {
+        let store = MemoryBlockStore::new();
+        let cid = cid_for(RAW, b"original");
+        store
+            .put(&cid, Bytes::from_static(b"tampered"))
+            .await
+            .unwrap();
+
+        let err = get_block(&store, &cid).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<IntegrityError>().unwrap().cid, cid);
+        // The store itself hands back whatever it holds.
+        assert_eq!(store.get(&cid).await.unwrap(), &b"tampered"[..]);
+    }
+}
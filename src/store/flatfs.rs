@@ -0,0 +1,90 @@
+use anyhow::{anyhow, Result};
+
+use async_trait::async_trait;
+
+use bytes::Bytes;
+
+use libipld::cid::Cid;
+
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use tokio::fs;
+
+use super::BlockStore;
+
+/// Length of the shard directory names, taken from the end of the key.
+const SHARD_LEN: usize = 2;
+
+/// A [`BlockStore`](BlockStore) that keeps each block in its own file under `root`.
+///
+/// Like go-ipfs' flatfs, blocks are sharded into directories named after the
+/// next-to-last [`SHARD_LEN`](SHARD_LEN) characters of their key so that no
+/// single directory grows too large.
+pub struct FlatFsBlockStore {
+    root: PathBuf,
+}
+
+impl FlatFsBlockStore {
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        FlatFsBlockStore {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    fn path(&self, cid: &Cid) -> PathBuf {
+        let key = cid.to_string();
+        let end = key.len() - 1;
+        let shard = &key[end - SHARD_LEN..end];
+        self.root.join(shard).join(format!("{}.data", key))
+    }
+}
+
+#[async_trait]
+impl BlockStore for FlatFsBlockStore {
+    async fn put(&self, cid: &Cid, data: Bytes) -> Result<()> {
+        let path = self.path(cid);
+        if fs::metadata(&path).await.is_ok() {
+            return Ok(());
+        }
+
+        let dir = path.parent().unwrap();
+        fs::create_dir_all(dir)
+            .await
+            .map_err(|e| anyhow!("Error creating `{}`: {}", dir.display(), e))?;
+
+        // Write to a temporary file first so a crash never leaves a truncated block behind.
+        let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+        fs::write(&tmp, &data)
+            .await
+            .map_err(|e| anyhow!("Error writing `{}`: {}", tmp.display(), e))?;
+        fs::rename(&tmp, &path)
+            .await
+            .map_err(|e| anyhow!("Error writing `{}`: {}", path.display(), e))?;
+        Ok(())
+    }
+
+    async fn get(&self, cid: &Cid) -> Result<Bytes> {
+        let path = self.path(cid);
+        match fs::read(&path).await {
+            Ok(data) => Ok(data.into()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Err(anyhow!("Block `{}` not found", cid)),
+            Err(e) => Err(anyhow!("Error reading `{}`: {}", path.display(), e)),
+        }
+    }
+
+    async fn has(&self, cid: &Cid) -> Result<bool> {
+        match fs::metadata(self.path(cid)).await {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn delete(&self, cid: &Cid) -> Result<()> {
+        match fs::remove_file(self.path(cid)).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
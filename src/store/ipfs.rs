@@ -0,0 +1,92 @@
+use anyhow::{anyhow, bail, ensure, Result};
+
+use async_trait::async_trait;
+
+use bytes::Bytes;
+
+use futures::TryStreamExt;
+
+use ipfs_api_backend_hyper::request::BlockPut;
+use ipfs_api_backend_hyper::{BackendWithGlobalOptions, GlobalOptions, IpfsApi};
+
+use ipfs_api_prelude::Backend;
+
+use libipld::cid::Cid;
+
+use std::io::Cursor;
+
+use super::{BlockStore, DAG_CBOR, RAW};
+
+/// A [`BlockStore`](BlockStore) backed by the block API of an ipfs daemon.
+pub struct IpfsBlockStore<B> {
+    client: B,
+    /// The same client, but without the daemon going to the network for blocks
+    /// it doesn't have, which would make [`has`](BlockStore::has) wait on them.
+    local: BackendWithGlobalOptions<B>,
+}
+
+impl<B: Backend + Clone> IpfsBlockStore<B> {
+    pub fn new(client: B) -> Self {
+        let offline = GlobalOptions::builder().offline(true).build();
+        IpfsBlockStore {
+            local: BackendWithGlobalOptions::new(client.clone(), offline),
+            client,
+        }
+    }
+}
+
+#[async_trait]
+impl<B: Backend + Clone> BlockStore for IpfsBlockStore<B> {
+    async fn put(&self, cid: &Cid, data: Bytes) -> Result<()> {
+        let format = match cid.codec() {
+            RAW => "raw",
+            DAG_CBOR => "dag-cbor",
+            codec => bail!("Unsupported codec {:#x} for `{}`", codec, cid),
+        };
+        let opts = BlockPut::builder()
+            .format(format)
+            .mhtype("sha2-256")
+            .build();
+        let res = self
+            .client
+            .block_put_with_options(Cursor::new(data), opts)
+            .await
+            .map_err(|e| anyhow!("Error putting block `{}`: {}", cid, e))?;
+
+        // The daemon may hand back a different CID version, but the hash must agree.
+        let key = Cid::try_from(res.key.as_str())?;
+        ensure!(
+            key.hash() == cid.hash(),
+            "ipfs stored block `{}` as `{}`",
+            cid,
+            key
+        );
+        Ok(())
+    }
+
+    async fn get(&self, cid: &Cid) -> Result<Bytes> {
+        let data = self
+            .client
+            .block_get(cid.to_string().as_str())
+            .map_ok(|chunk| chunk.to_vec())
+            .try_concat()
+            .await
+            .map_err(|e| anyhow!("Error fetching block `{}`: {}", cid, e))?;
+        Ok(data.into())
+    }
+
+    async fn has(&self, cid: &Cid) -> Result<bool> {
+        match self.local.block_stat(cid.to_string().as_str()).await {
+            Ok(_) => Ok(true),
+            Err(ipfs_api_backend_hyper::Error::Api(_)) => Ok(false),
+            Err(e) => bail!("Error checking for block `{}`: {}", cid, e),
+        }
+    }
+
+    async fn delete(&self, cid: &Cid) -> Result<()> {
+        match self.client.block_rm(cid.to_string().as_str()).await {
+            Ok(_) | Err(ipfs_api_backend_hyper::Error::Api(_)) => Ok(()),
+            Err(e) => bail!("Error removing block `{}`: {}", cid, e),
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use anyhow::{anyhow, Result};
+
+use async_trait::async_trait;
+
+use bytes::Bytes;
+
+use libipld::cid::Cid;
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use super::BlockStore;
+
+/// A [`BlockStore`](BlockStore) that keeps every block in memory.
+///
+/// Useful for tests, and for building up a DAG before copying it somewhere more
+/// permanent with [`flush`](MemoryBlockStore::flush).
+#[derive(Debug, Default)]
+pub struct MemoryBlockStore {
+    blocks: RwLock<HashMap<Cid, Bytes>>,
+}
+
+// The CLI only goes through the `BlockStore` impl, so the rest is just for tests.
+#[cfg_attr(not(test), allow(dead_code))]
+impl MemoryBlockStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of blocks in the store.
+    pub fn len(&self) -> usize {
+        self.blocks.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Copy every block in this store into `dest`.
+    pub async fn flush<S: BlockStore + ?Sized>(&self, dest: &S) -> Result<()> {
+        let blocks: Vec<(Cid, Bytes)> = self
+            .blocks
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(cid, data)| (*cid, data.clone()))
+            .collect();
+        for (cid, data) in blocks {
+            dest.put(&cid, data).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BlockStore for MemoryBlockStore {
+    async fn put(&self, cid: &Cid, data: Bytes) -> Result<()> {
+        self.blocks.write().unwrap().insert(*cid, data);
+        Ok(())
+    }
+
+    async fn get(&self, cid: &Cid) -> Result<Bytes> {
+        self.blocks
+            .read()
+            .unwrap()
+            .get(cid)
+            .cloned()
+            .ok_or_else(|| anyhow!("Block `{}` not found", cid))
+    }
+
+    async fn has(&self, cid: &Cid) -> Result<bool> {
+        Ok(self.blocks.read().unwrap().contains_key(cid))
+    }
+
+    async fn delete(&self, cid: &Cid) -> Result<()> {
+        self.blocks.write().unwrap().remove(cid);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{cid_for, RAW};
+
+    #[tokio::test]
+    async fn flush_copies_every_block() {
+        let store = MemoryBlockStore::new();
+        assert!(store.is_empty());
+        let blocks = [&b"one"[..], b"two", b"three"];
+        for data in blocks {
+            let cid = cid_for(RAW, data);
+            store.put(&cid, Bytes::from_static(data)).await.unwrap();
+        }
+        assert_eq!(store.len(), 3);
+
+        let dest = MemoryBlockStore::new();
+        store.flush(&dest).await.unwrap();
+        assert_eq!(dest.len(), 3);
+        for data in blocks {
+            assert_eq!(dest.get(&cid_for(RAW, data)).await.unwrap(), data);
+        }
+    }
+
+    #[tokio::test]
+    async fn delete_removes_block() {
+        let store = MemoryBlockStore::new();
+        let cid = cid_for(RAW, b"gone");
+        store.put(&cid, Bytes::from_static(b"gone")).await.unwrap();
+        assert!(store.has(&cid).await.unwrap());
+
+        store.delete(&cid).await.unwrap();
+        assert!(!store.has(&cid).await.unwrap());
+        // Deleting it again is fine.
+        store.delete(&cid).await.unwrap();
+    }
+}
@@ -1,60 +1,189 @@
-use anyhow::{anyhow, bail, ensure, Error, Result};
+use anyhow::{anyhow, bail, ensure, Result};
 
+use futures::future::BoxFuture;
+use futures::io::{AsyncRead, AsyncSeek};
+use futures::ready;
 use futures::FutureExt;
 
-use futures::StreamExt;
-use futures::TryFutureExt;
-use futures::TryStreamExt;
-
-use ipfs_api_backend_hyper::request::BlockPut;
-
-use ipfs_api_backend_hyper::IpfsApi;
-
-use itertools::Itertools;
+use bytes::Bytes;
 
+use libipld::cbor::DagCborCodec;
 use libipld::cid::Cid;
 use libipld::DagCbor;
+use libipld::Ipld;
 use libipld::Link;
 
 use libipld::prelude::*;
 
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::CString;
+use std::fmt::Display;
+use std::fs::Permissions;
 use std::io::prelude::*;
-use std::io::Cursor;
+use std::io::{ErrorKind, SeekFrom};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::chunker::{self, Chunker, Chunks};
+use crate::store::{self, BlockStore};
+
+pub mod archive;
+pub mod diff;
+mod error;
+pub mod hamt;
+pub mod merge;
+
+pub use error::UnixfsError;
 
-use fill::Chunk;
+use hamt::{Hamt, ShardedDirectory};
 
 #[derive(Clone, DagCbor, Debug, Eq, PartialEq)]
 pub struct File {
     data: Vec<FileDataEntry>,
+    /// The whole contents of a small file, stored in place of any `data` entries.
+    #[ipld(default = None)]
+    inline: Option<Box<[u8]>>,
     size: u64,
     #[ipld(rename = "type")]
     ty: String,
+    /// The spec of the [`Chunker`](Chunker) the data was split with, if known.
+    #[ipld(default = None)]
+    chunker: Option<String>,
+    /// The version of this file it was derived from, if any.
+    #[ipld(default = None)]
+    previous: Option<super::Link>,
+    /// When this version was created, in seconds since the unix epoch.
+    #[ipld(default = None)]
+    timestamp: Option<u64>,
+    /// Who created this version.
+    #[ipld(default = None)]
+    author: Option<String>,
+    #[ipld(default = Metadata::default())]
+    meta: Metadata,
 }
 
 impl File {
-    fn new(mut data: Vec<FileDataEntry>) -> Result<Self> {
-        if data.is_empty() {
-            return Ok(File {
-                data: Vec::new(),
-                size: 0,
-                ty: "file".to_string(),
+    /// A file made of the `data` entries, in any order. The indices in any
+    /// [`UnixfsError`](UnixfsError) refer to that order, not the sorted one.
+    fn new(data: Vec<FileDataEntry>, chunker: Option<String>) -> Result<Self, UnixfsError> {
+        let mut order = (0..data.len()).collect::<Vec<usize>>();
+        order.sort_unstable_by_key(|&i| &data[i]);
+        let data = order.iter().map(|&i| data[i].clone()).collect::<Vec<_>>();
+        let size = data.last().map_or(0, |entry| entry.bounds.1);
+        let file = File {
+            data,
+            inline: None,
+            size,
+            ty: FILE_TYPE.to_string(),
+            chunker,
+            previous: None,
+            timestamp: None,
+            author: None,
+            meta: Metadata::default(),
+        };
+        file.validate().map_err(|e| e.map_index(|i| order[i]))?;
+        Ok(file)
+    }
+
+    /// A file holding `data` inline, without any blocks of its own.
+    fn new_inline(data: Vec<u8>, chunker: Option<String>) -> Result<Self> {
+        let mut file = File::new(Vec::new(), chunker)?;
+        file.size = data.len().try_into()?;
+        file.inline = Some(data.into_boxed_slice());
+        Ok(file)
+    }
+
+    /// Check that the data entries cover exactly `0..size`, in order and without
+    /// gaps or overlaps, or that there are none if the data is inline. Nodes further
+    /// down are checked as they are fetched.
+    pub fn validate(&self) -> Result<(), UnixfsError> {
+        let end = match (&self.inline, self.data.first(), self.data.last()) {
+            (Some(_), Some(_), _) => {
+                return Err(UnixfsError::InlineWithEntries {
+                    entries: self.data.len(),
+                })
+            }
+            (Some(inline), None, _) => inline.len() as u64,
+            (None, Some(first), Some(last)) => {
+                if first.bounds.0 != 0 {
+                    return Err(UnixfsError::NotStartingAtZero {
+                        start: first.bounds.0,
+                    });
+                }
+                check_entries(&self.data)?;
+                last.bounds.1
+            }
+            (None, _, _) => 0,
+        };
+        if end != self.size {
+            return Err(UnixfsError::SizeMismatch {
+                size: self.size,
+                end,
             });
         }
-        data.sort_unstable();
-        ensure!(data[0].bounds.0 == 0, "Invaalid file data range");
-        for i in 0..(data.len() - 1) {
-            ensure!(
-                data[i].bounds.1 == data[i + 1].bounds.0 + 1,
-                "Invalid file data range"
-            );
+        Ok(())
+    }
+
+    /// The spec of the [`Chunker`](Chunker) the data was split with, if known.
+    pub fn chunker(&self) -> Option<&str> {
+        self.chunker.as_deref()
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// The version of this file it was derived from, if any.
+    pub fn previous(&self) -> Option<&Cid> {
+        self.previous.as_ref().map(|link| link.cid())
+    }
+
+    /// When this version was created, in seconds since the unix epoch.
+    pub fn timestamp(&self) -> Option<u64> {
+        self.timestamp
+    }
+
+    pub fn author(&self) -> Option<&str> {
+        self.author.as_deref()
+    }
+
+    pub fn metadata(&self) -> &Metadata {
+        &self.meta
+    }
+}
+
+/// Check that each entry of `data` covers some bytes, and starts where the one
+/// before it ends.
+fn check_entries(data: &[FileDataEntry]) -> Result<(), UnixfsError> {
+    for (index, entry) in data.iter().enumerate() {
+        let FileDataBounds(start, end) = entry.bounds;
+        if start >= end {
+            return Err(UnixfsError::EmptyEntry { index, start, end });
+        }
+        if let Some(next) = data.get(index + 1) {
+            let next = next.bounds.0;
+            if end < next {
+                return Err(UnixfsError::Gap { index, end, next });
+            }
+            if end > next {
+                return Err(UnixfsError::Overlap { index, end, next });
+            }
         }
-        let size = data.last().unwrap().bounds.1;
-        Ok(File {
-            data,
-            size,
-            ty: "file".to_string(),
-        })
     }
+    Ok(())
+}
+
+/// The entries of the sorted `data` whose bounds overlap the byte range `start..end`.
+fn overlapping(data: &[FileDataEntry], start: u64, end: u64) -> &[FileDataEntry] {
+    let first = data.partition_point(|entry| entry.bounds.1 <= start);
+    let last = data.partition_point(|entry| entry.bounds.0 < end);
+    &data[first..last.max(first)]
 }
 
 #[derive(Clone, DagCbor, Debug, Eq, PartialEq)]
@@ -72,15 +201,18 @@ impl Ord for FileDataBounds {
     }
 }
 
+/// A range of a file's data, stored in the raw block `link`, or split further by
+/// the [`FileData`](FileData) node `link`, or a hole reading as zeros if there is no link.
 #[derive(Clone, DagCbor, Debug, Eq, PartialEq)]
 struct FileDataEntry {
     bounds: FileDataBounds,
-    link: super::Link,
+    #[ipld(default = None)]
+    link: Option<super::Link>,
 }
 
 impl PartialOrd for FileDataEntry {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        self.bounds.partial_cmp(&other.bounds)
+        Some(self.cmp(other))
     }
 }
 
@@ -95,282 +227,1650 @@ impl FileDataEntry {
         let s64: u64 = size.try_into()?;
         Ok(FileDataEntry {
             bounds: FileDataBounds(pos, pos + s64),
-            link: Link::new(cid),
+            link: Some(Link::new(cid)),
         })
     }
+
+    fn hole(start: u64, end: u64) -> Self {
+        FileDataEntry {
+            bounds: FileDataBounds(start, end),
+            link: None,
+        }
+    }
+
+    /// The [`FileData`](FileData) node this entry links to, unless it is a leaf.
+    fn node(&self) -> Option<&Cid> {
+        self.link
+            .as_ref()
+            .map(|link| link.cid())
+            .filter(|cid| cid.codec() == store::DAG_CBOR)
+    }
 }
 
-const BLOCK_SIZE: usize = 262144;
-
-/// Import the data in the reader `read` into ipfs via the `client`. Chunk it into
-/// [`BLOCK_SIZE`](BLOCK_SIZE) sized chunks.
-pub async fn import_file<R: Read + Chunk, B: IpfsApi>(read: R, client: B) -> Result<(File, Cid)> {
-    let mut cum_size = 0;
-    let f = futures::stream::iter(read.chunked(BLOCK_SIZE)).and_then(|data| {
-        let len = data.len();
-        client.block_put_with_options(Cursor::new(data), BlockPut::builder().format("raw").build())
-    });
-    /*
-    .and_then(|data| {
-        let opts = BlockPut::builder().format("raw").build();
-        let len = data.len();
-        client
-            .block_put_with_options(Cursor::new(data), opts)
-            .map_err(|e| anyhow!("{}", e))
-            .map(move |res| match res {
-                Err(e) => Err(e),
-                Ok(res) => Ok((len, super::parse_cid(res.key.as_str()))),
-            })
-    })
-    .map_ok(|(len, cid)| {
-            let rv = FileDataEntry::new(cum_size, len, cid);
-            match TryInto::<u64>::try_into(len) {
-                Err(e) => bail!(e),
-                Ok(len) => {
-                    cum_size += len;
-                    Ok(rv)
+/// An intermediate node of a large file, splitting the range of the entry linking
+/// to it between its own entries.
+#[derive(Clone, DagCbor, Debug, Eq, PartialEq)]
+struct FileData {
+    data: Vec<FileDataEntry>,
+    #[ipld(rename = "type")]
+    ty: String,
+}
+
+/// The most entries a [`File`](File) or [`FileData`](FileData) node holds, which
+/// keeps them under a few hundred KiB.
+const MAX_ENTRIES: usize = 4096;
+
+/// Group `data` under [`FileData`](FileData) nodes a level at a time, until no more
+/// than [`MAX_ENTRIES`](MAX_ENTRIES) remain for the root. Every leaf ends up at the
+/// same depth, so finding the one at any offset takes one fetch per level.
+async fn balance<S>(store: &S, mut data: Vec<FileDataEntry>) -> Result<Vec<FileDataEntry>>
+where
+    S: BlockStore + ?Sized,
+{
+    while data.len() > MAX_ENTRIES {
+        let mut parents = Vec::with_capacity(data.len().div_ceil(MAX_ENTRIES));
+        for children in data.chunks(MAX_ENTRIES) {
+            let bounds =
+                FileDataBounds(children[0].bounds.0, children[children.len() - 1].bounds.1);
+            let node = FileData {
+                data: children.to_vec(),
+                ty: FILE_DATA_TYPE.to_string(),
+            };
+            let cid = store::put_node(store, &node).await?;
+            parents.push(FileDataEntry {
+                bounds,
+                link: Some(Link::new(cid)),
+            });
+        }
+        data = parents;
+    }
+    Ok(data)
+}
+
+/// Fetch the entries of the [`FileData`](FileData) node `cid`, checking that it is
+/// one and that they cover the `bounds` of the entry that linked to it.
+async fn get_children<S>(
+    store: &S,
+    cid: &Cid,
+    bounds: &FileDataBounds,
+) -> Result<Vec<FileDataEntry>>
+where
+    S: BlockStore + ?Sized,
+{
+    let node: FileData = store::get_node(store, cid).await?;
+    ensure!(
+        node.ty == FILE_DATA_TYPE,
+        "Node `{}` has type `{}` instead of `{}`",
+        cid,
+        node.ty,
+        FILE_DATA_TYPE
+    );
+    check_entries(&node.data)?;
+    let start = node.data.first().map_or(0, |entry| entry.bounds.0);
+    let end = node.data.last().map_or(0, |entry| entry.bounds.1);
+    if (start, end) != (bounds.0, bounds.1) {
+        return Err(UnixfsError::NodeBounds {
+            cid: Box::new(*cid),
+            start,
+            end,
+            expected_start: bounds.0,
+            expected_end: bounds.1,
+        }
+        .into());
+    }
+    Ok(node.data)
+}
+
+/// Every leaf under `data` whose bounds overlap `start..end`, in order, fetching
+/// whichever [`FileData`](FileData) nodes they are under.
+fn leaves<'a, S>(
+    store: &'a S,
+    data: &'a [FileDataEntry],
+    start: u64,
+    end: u64,
+) -> BoxFuture<'a, Result<Vec<FileDataEntry>>>
+where
+    S: BlockStore + ?Sized,
+{
+    async move {
+        let mut out = Vec::new();
+        for entry in overlapping(data, start, end) {
+            match entry.node() {
+                Some(cid) => {
+                    let children = get_children(store, cid, &entry.bounds).await?;
+                    out.extend(leaves(store, &children, start, end).await?);
                 }
+                None => out.push(entry.clone()),
             }
-    });*/
-    // .collect::<Result<Vec<FileDataBounds>>>();
-    /*.map_ok(|(len, cid)| {
-        match cid {
-            Err(e) => std::io::Error::new(ErrorKind::Other, format!("{}", e)),
-            Ok(cid) => Ok(FileDataEntry::new(cum_size, len, cid))
-        }
-    })*/
-
-    // .fold_map(0, |size, cid| {
-
-    //});
-
-    //let u = unfold((f, 0), |state| {
-
-    //});
-
-    // file_data.push();
-    // cum_size += TryInto::<u64>::try_into(bytes_read)?;
-
-    // let file = File::new(file_data)?;
-
-    // let mut bytes = Vec::new();
-    // file.encode(DagCborCodec, &mut bytes)?;
-    // let res = match client
-    //     .dag_put_with_options(
-    //         std::io::Cursor::new(bytes),
-    //         DagPut::builder().input_codec("dag-cbor").build(),
-    //     )
-    //     .await
-    // {
-    //     Err(e) => bail!("{}", e),
-    //     Ok(res) => res,
-    // };
-    bail!("IDK");
-    // Ok((file, parse_cid(&res.cid.cid_string)?))
-}
-
-// pub struct FileReader<B: IpfsApi> {
-//     file: Cid,
-//     client: B,
-// }
-//
-// impl<B: IpfsApi> FileReader<B> {
-//     pub fn new(file: Cid, client: B) -> Self {
-//         let _cid = file;
-//         let fut = client
-//             .dag_get_with_codec(file.to_string().as_str(), "dag-cbor")
-//             .map_ok(|chunk| chunk.to_vec())
-//             .try_concat()
-//             .map(move |data| match data {
-//                 Err(e) => bail!("Error fetching file data for `{}`: {}", file, e),
-//                 Ok(data) => DagCborCodec.decode::<File>(data.as_slice()),
-//             });
-//
-//         FileReader::<B> {
-//             file,
-//             state: Arc::new(Mutex::new(state)),
-//         }
-//     }
-// }
-//
-// impl<B: IpfsApi> AsyncRead for FileReader<B> {
-//     // fn poll_read(
-//     //     self: std::pin::Pin<&mut Self>,
-//     //     cx: &mut std::task::Context<'_>,
-//     //     buf: &mut [u8],
-//     // ) -> Poll<std::io::Result<usize>> {
-//     //     let mut state = match self.state.lock() {
-//     //         Err(e) => {
-//     //             return Poll::Ready(Err(std::io::Error::new(
-//     //                 ErrorKind::Other,
-//     //                 format!("Poisoned Mutex: {}", e),
-//     //             )))
-//     //         }
-//     //         Ok(state) => state,
-//     //     };
-//
-//     //     if state.file.is_none() {
-//     //         match state.file_fut.poll_unpin(cx) {
-//     //             Poll::Pending => {
-//     //                 return Poll::Pending;
-//     //             }
-//     //             Poll::Ready(file) => match file {
-//     //                 Err(e) => {
-//     //                     return Poll::Ready(Err(std::io::Error::new(
-//     //                         ErrorKind::Other,
-//     //                         format!("{}", e),
-//     //                     )));
-//     //                 }
-//     //                 Ok(file) => {
-//     //                     state.file = Some(file);
-//     //                 }
-//     //             },
-//     //         }
-//     //     }
-//
-//     //     // This should be true now
-//     //     assert!(state.file.is_some());
-//
-//     //     let l = state.buf.read(buf)?;
-//     //     if l != 0 {
-//     //         state.pos += match TryInto::<u64>::try_into(l) {
-//     //             Err(e) => {
-//     //                 return Poll::Ready(Err(std::io::Error::new(
-//     //                     ErrorKind::Other,
-//     //                     format!("{}", e),
-//     //                 )));
-//     //             }
-//     //             Ok(l) => l,
-//     //         };
-//     //         return Poll::Ready(Ok(l));
-//     //     } else {
-//     //         // Buffer is empty
-//     //         let chunk = match state
-//     //             .file
-//     //             .as_ref()
-//     //             .unwrap()
-//     //             .data
-//     //             .iter()
-//     //             .find(|entry| entry.bounds.0 == state.pos)
-//     //         {
-//     //             None => {
-//     //                 // Couldn't find the next block, so EOF.
-//     //                 return Poll::Ready(Ok(0));
-//     //             }
-//     //             Some(chunk) => chunk,
-//     //         };
-//
-//     //         match &state.request {
-//     //             None => {
-//     //                 // Start a request for the next chunk if one doesn't exist
-//     //                 let waker = cx.waker().clone();
-//     //                 state.request = Some((
-//     //                     chunk.clone(),
-//     //                     Box::new(
-//     //                         state
-//     //                             .client
-//     //                             .block_get(chunk.link.to_string().as_str())
-//     //                             .map_ok(|result| result.to_vec())
-//     //                             .try_concat()
-//     //                             .map_err(|e| anyhow!("{}", e))
-//     //                             .map(move |result| {
-//     //                                 waker.wake();
-//     //                                 result
-//     //                             }),
-//     //                     ),
-//     //                 ));
-//
-//     //                 return Poll::Pending;
-//     //             }
-//     //             Some((_bounds, _request)) => {
-//     //                 // match request.poll_unpin(cx) {
-//     //                 //     Poll::Pending => {
-//     //                 //         // Needs to handle the waker properly. Must clone it again.
-//     //                 //         return Poll::Pending;
-//     //                 //     }
-//     //                 //     Poll::Ready(data) => {
-//     //                 //         return Poll::Pending;
-//     //                 //     }
-//     //                 // }
-//     //                 return Poll::Pending;
-//     //             }
-//     //         }
-//     //     }
-//
-//     //     Poll::Ready(Ok(0))
-//     // }
-//
-//     fn poll_read(
-//         self: std::pin::Pin<&mut Self>,
-//         cx: &mut std::task::Context<'_>,
-//         buf: &mut [u8],
-//     ) -> Poll<std::io::Result<usize>> {
-//     }
-// }
-//
-// impl<B: IpfsApi> AsyncSeek for FileReader<B> {
-//     fn poll_seek(
-//         self: std::pin::Pin<&mut Self>,
-//         _cx: &mut std::task::Context<'_>,
-//         _pos: std::io::SeekFrom,
-//     ) -> Poll<std::io::Result<u64>> {
-//         Poll::Ready(Ok(0))
-//     }
-// }
-//
-// fn parse_file_data() {}
-//
-// // https://github.com/ipfs/go-unixfs/tree/master/hamt
-// pub mod hamt {
-//
-//     use anyhow::{ensure, Result};
-//     use bitvec::prelude::*;
-//
-//     use murmur3::murmur3_x64_128;
-//
-//     fn chunk_to_u8<O: BitOrder, T: BitStore>(chunk: &BitSlice<O, T>) -> Result<u8> {
-//         chunk.iter().enumerate().fold(Ok(0_u8), |rv, (i, b)| {
-//             let a: u8 = Into::<u8>::into(*b) * 2_u8.pow(i.try_into()?);
-//             Ok(rv? + a)
-//         })
-//     }
-//
-//     fn split_hash(hash: u64, n: u8, offset: u8) -> Result<u8> {
-//         ensure!((1..=8).contains(&n));
-//         ensure!(Into::<usize>::into(offset) <= (64_usize / Into::<usize>::into(n)));
-//
-//         println!("{}", hash.view_bits::<Msb0>());
-//
-//         let chunks = hash
-//             .view_bits::<Msb0>()
-//             .chunks(n.into())
-//             .map(chunk_to_u8)
-//             .collect::<Result<Vec<u8>>>()?;
-//
-//         Ok(chunks[Into::<usize>::into(offset)])
-//     }
-//
-//     fn compute_hash<T>(read: &mut T) -> Result<u64>
-//     where
-//         T: std::io::Read,
-//     {
-//         let hash = murmur3_x64_128(read, 0)?;
-//         let buf16: [u8; 16] = hash.to_be_bytes();
-//         let buf8: [u8; 8] = buf16[0..8].try_into()?;
-//         Ok(u64::from_be_bytes(buf8))
-//     }
-//
-//     pub fn test() {
-//         let hash = compute_hash(&mut std::io::Cursor::new(
-//             "Hello, World! Foobarbaz 3.141592653589",
-//         ))
-//         .unwrap();
-//         println!("{}", hash);
-//         println!(
-//             "{:?}",
-//             (0_u8..11)
-//                 .map(|i| { split_hash(hash, 6, i).unwrap() })
-//                 .collect::<Vec<u8>>()
-//         );
-//     }
-// }
+        }
+        Ok(out)
+    }
+    .boxed()
+}
+
+/// Filesystem metadata carried by every type of node. Every field is optional, so
+/// that content imported from somewhere without the metadata still dedupes.
+#[derive(Clone, DagCbor, Debug, Default, Eq, PartialEq)]
+pub struct Metadata {
+    /// Unix permission bits, e.g. `0o644`.
+    #[ipld(default = None)]
+    pub mode: Option<u32>,
+    /// Modification time, in seconds since the unix epoch.
+    #[ipld(default = None)]
+    pub mtime: Option<i64>,
+    /// Nanoseconds past `mtime`.
+    #[ipld(default = None)]
+    pub mtime_nsecs: Option<u32>,
+    #[ipld(default = None)]
+    pub uid: Option<u32>,
+    #[ipld(default = None)]
+    pub gid: Option<u32>,
+    /// Extended attributes, by full name, e.g. `user.comment`.
+    #[ipld(default = None)]
+    pub xattrs: Option<BTreeMap<String, Box<[u8]>>>,
+}
+
+impl Metadata {
+    pub fn from_fs(meta: &std::fs::Metadata) -> Self {
+        Metadata {
+            mode: Some(meta.mode() & 0o7777),
+            mtime: Some(meta.mtime()),
+            mtime_nsecs: meta.mtime_nsec().try_into().ok(),
+            uid: Some(meta.uid()),
+            gid: Some(meta.gid()),
+            xattrs: None,
+        }
+    }
+
+    /// Read the metadata of the file at `path`, including the extended attributes
+    /// selected by `filter`. Symlinks are only followed if `deref` is set.
+    pub fn from_path(path: &Path, deref: bool, filter: &XattrFilter) -> Result<Self> {
+        let err = |e| anyhow!("Couldn't read metadata of `{}`: {}", path.display(), e);
+        let (meta, names) = if deref {
+            (std::fs::metadata(path), xattr::list_deref(path))
+        } else {
+            (std::fs::symlink_metadata(path), xattr::list(path))
+        };
+        let names = match names {
+            // Not every filesystem has extended attributes.
+            Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => None,
+            names => Some(names.map_err(err)?),
+        };
+
+        let mut xattrs = BTreeMap::new();
+        for name in names.into_iter().flatten() {
+            let name = name.into_string().map_err(|name| {
+                anyhow!("Non UTF-8 attribute {:?} on `{}`", name, path.display())
+            })?;
+            if !filter.matches(&name) {
+                continue;
+            }
+            let value = if deref {
+                xattr::get_deref(path, &name)
+            } else {
+                xattr::get(path, &name)
+            };
+            // The attribute may have been removed since it was listed.
+            if let Some(value) = value.map_err(err)? {
+                xattrs.insert(name, value.into_boxed_slice());
+            }
+        }
+
+        Ok(Metadata {
+            xattrs: Some(xattrs).filter(|xattrs| !xattrs.is_empty()),
+            ..Metadata::from_fs(&meta.map_err(err)?)
+        })
+    }
+
+    /// Apply this metadata to the file at `path`, without following symlinks.
+    fn restore(&self, path: &Path, opts: &ExportOptions) -> Result<()> {
+        let err = |e| anyhow!("Couldn't set metadata on `{}`: {}", path.display(), e);
+
+        // Ownership goes first, since changing it can clear the setuid and setgid bits,
+        // as well as file capabilities.
+        if opts.owner && (self.uid.is_some() || self.gid.is_some()) {
+            std::os::unix::fs::lchown(path, self.uid, self.gid).map_err(err)?;
+        }
+
+        let xattrs = self.xattrs.iter().flatten();
+        for (name, value) in xattrs.filter(|(name, _)| opts.xattrs.matches(name)) {
+            xattr::set(path, name, value).map_err(err)?;
+        }
+
+        // chmod would follow a symlink, and their permissions aren't used anyway.
+        let is_symlink = std::fs::symlink_metadata(path)
+            .map_err(err)?
+            .file_type()
+            .is_symlink();
+        if let (Some(mode), false) = (self.mode, is_symlink) {
+            std::fs::set_permissions(path, Permissions::from_mode(mode)).map_err(err)?;
+        }
+
+        if let Some(mtime) = self.mtime {
+            let omit = libc::timespec {
+                tv_sec: 0,
+                tv_nsec: libc::UTIME_OMIT,
+            };
+            let mtime = libc::timespec {
+                tv_sec: mtime as libc::time_t,
+                tv_nsec: self.mtime_nsecs.unwrap_or(0) as libc::c_long,
+            };
+            let c_path = CString::new(path.as_os_str().as_bytes())?;
+            // SAFETY: `c_path` is a valid C string and `times` holds two timespecs.
+            let ret = unsafe {
+                libc::utimensat(
+                    libc::AT_FDCWD,
+                    c_path.as_ptr(),
+                    [omit, mtime].as_ptr(),
+                    libc::AT_SYMLINK_NOFOLLOW,
+                )
+            };
+            if ret != 0 {
+                return Err(err(std::io::Error::last_os_error()));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Selects extended attributes by namespace, the part of their name before the first `.`.
+#[derive(Clone, Debug, Default)]
+pub struct XattrFilter {
+    /// Namespaces to keep, e.g. `user`. Every namespace is kept if this is empty.
+    pub include: Vec<String>,
+    /// Namespaces to skip, e.g. `security`, even if they are also included.
+    pub exclude: Vec<String>,
+}
+
+impl XattrFilter {
+    pub fn matches(&self, name: &str) -> bool {
+        let namespace = name.split('.').next().unwrap_or(name);
+        (self.include.is_empty() || self.include.iter().any(|ns| ns == namespace))
+            && !self.exclude.iter().any(|ns| ns == namespace)
+    }
+}
+
+/// Options controlling how [`import_dir`](import_dir) reads a tree from the filesystem.
+#[derive(Clone, Debug)]
+pub struct ImportOptions {
+    /// Import whatever symbolic links point to, instead of the links themselves.
+    pub dereference: bool,
+    /// Which extended attributes to record.
+    pub xattrs: XattrFilter,
+    /// Files up to this many bytes are stored inline in their node.
+    pub inline_limit: usize,
+}
+
+impl Default for ImportOptions {
+    fn default() -> Self {
+        ImportOptions {
+            dereference: false,
+            xattrs: XattrFilter::default(),
+            inline_limit: DEFAULT_INLINE_LIMIT,
+        }
+    }
+}
+
+/// Options controlling how [`export`](export) writes a tree to the filesystem.
+#[derive(Clone, Debug)]
+pub struct ExportOptions {
+    /// Restore the owner and group of each file, which usually needs root.
+    pub owner: bool,
+    /// Which of the recorded extended attributes to restore.
+    pub xattrs: XattrFilter,
+    /// Recreate files that were imported as hard links to each other as hard links.
+    pub hardlinks: bool,
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        ExportOptions {
+            owner: true,
+            xattrs: XattrFilter::default(),
+            hardlinks: false,
+        }
+    }
+}
+
+/// Hard link groups of directory entries, by entry name. Entries anywhere in a tree
+/// with the same group were links to the same file when it was imported.
+pub type HardLinks = BTreeMap<String, u64>;
+
+/// A directory, mapping entry names to the nodes they contain.
+#[derive(Clone, DagCbor, Debug, Eq, PartialEq)]
+pub struct Directory {
+    entries: BTreeMap<String, super::Link>,
+    #[ipld(rename = "type")]
+    ty: String,
+    #[ipld(default = Metadata::default())]
+    meta: Metadata,
+    #[ipld(default = None)]
+    hardlinks: Option<HardLinks>,
+}
+
+impl Directory {
+    fn new(entries: BTreeMap<String, Cid>, meta: Metadata, hardlinks: HardLinks) -> Result<Self> {
+        for name in entries.keys() {
+            ensure!(valid_name(name), "Invalid directory entry name `{}`", name);
+        }
+        for name in hardlinks.keys() {
+            ensure!(
+                entries.contains_key(name),
+                "Hard link to missing entry `{}`",
+                name
+            );
+        }
+        Ok(Directory {
+            entries: entries
+                .into_iter()
+                .map(|(name, cid)| (name, Link::new(cid)))
+                .collect(),
+            ty: DIR_TYPE.to_string(),
+            meta,
+            hardlinks: Some(hardlinks).filter(|links| !links.is_empty()),
+        })
+    }
+
+    pub fn metadata(&self) -> &Metadata {
+        &self.meta
+    }
+
+    /// Iterate over the names and `Cid`s of the entries in this directory, in name order.
+    pub fn entries(&self) -> impl Iterator<Item = (&str, &Cid)> {
+        self.entries
+            .iter()
+            .map(|(name, link)| (name.as_str(), link.cid()))
+    }
+}
+
+/// A symbolic link to `target`, which is stored as is and not resolved.
+#[derive(Clone, DagCbor, Debug, Eq, PartialEq)]
+pub struct Symlink {
+    target: String,
+    #[ipld(rename = "type")]
+    ty: String,
+    #[ipld(default = Metadata::default())]
+    meta: Metadata,
+}
+
+impl Symlink {
+    pub fn new(target: String, meta: Metadata) -> Self {
+        Symlink {
+            target,
+            ty: SYMLINK_TYPE.to_string(),
+            meta,
+        }
+    }
+
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+
+    pub fn metadata(&self) -> &Metadata {
+        &self.meta
+    }
+}
+
+/// Whether `name` may be used as a directory entry, i.e. is a single path component.
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains('/') && !name.contains('\0')
+}
+
+/// Join the directory entry `name` onto the slash separated `path`.
+fn join(path: &str, name: &str) -> String {
+    if path.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", path, name)
+    }
+}
+
+/// A decoded node of any of the unixfs types.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Node {
+    File(File),
+    Directory(Directory),
+    ShardedDirectory(ShardedDirectory),
+    Symlink(Symlink),
+}
+
+impl Node {
+    pub fn is_dir(&self) -> bool {
+        matches!(self, Node::Directory(_) | Node::ShardedDirectory(_))
+    }
+
+    pub fn metadata(&self) -> &Metadata {
+        match self {
+            Node::File(file) => file.metadata(),
+            Node::Directory(dir) => dir.metadata(),
+            Node::ShardedDirectory(dir) => dir.metadata(),
+            Node::Symlink(link) => link.metadata(),
+        }
+    }
+
+    /// The hard link groups of the entries of this node, if it is a directory.
+    pub fn hardlinks(&self) -> Option<&HardLinks> {
+        match self {
+            Node::Directory(dir) => dir.hardlinks.as_ref(),
+            Node::ShardedDirectory(dir) => dir.hardlinks(),
+            Node::File(_) | Node::Symlink(_) => None,
+        }
+    }
+
+    /// The hard link group of the entry `name`, if this is a directory and it has one.
+    pub fn hard_link(&self, name: &str) -> Option<u64> {
+        self.hardlinks().and_then(|links| links.get(name).copied())
+    }
+}
+
+const FILE_TYPE: &str = "file";
+const DIR_TYPE: &str = "dir";
+const HAMT_TYPE: &str = "hamt";
+const SYMLINK_TYPE: &str = "symlink";
+const FILE_DATA_TYPE: &str = "filedata";
+
+/// Directories with more entries than this are stored as a [`Hamt`](Hamt).
+const SHARD_THRESHOLD: usize = 1024;
+
+/// How much of an import was already present in the store.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ImportStats {
+    /// Bytes of file data imported.
+    pub total_bytes: u64,
+    /// Number of chunks the data was split into.
+    pub chunks: u64,
+    /// Number of those chunks that were already in the store.
+    pub existing_chunks: u64,
+    /// Bytes of file data that had to be written to the store.
+    pub unique_bytes: u64,
+}
+
+impl ImportStats {
+    /// The ratio of imported bytes to bytes actually written, which is infinite if
+    /// every chunk was already in the store.
+    pub fn dedup_ratio(&self) -> f64 {
+        match (self.total_bytes, self.unique_bytes) {
+            (0, _) => 1.0,
+            (_, 0) => f64::INFINITY,
+            (total, unique) => total as f64 / unique as f64,
+        }
+    }
+}
+
+impl std::ops::AddAssign for ImportStats {
+    fn add_assign(&mut self, other: Self) {
+        self.total_bytes += other.total_bytes;
+        self.chunks += other.chunks;
+        self.existing_chunks += other.existing_chunks;
+        self.unique_bytes += other.unique_bytes;
+    }
+}
+
+impl Display for ImportStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "total bytes:     {}", self.total_bytes)?;
+        writeln!(f, "chunks:          {}", self.chunks)?;
+        writeln!(f, "existing chunks: {}", self.existing_chunks)?;
+        writeln!(f, "unique bytes:    {}", self.unique_bytes)?;
+        write!(f, "dedup ratio:     {:.2}", self.dedup_ratio())
+    }
+}
+
+/// Files up to this many bytes are stored inline in their node by default.
+pub const DEFAULT_INLINE_LIMIT: usize = 1024;
+
+/// Import the data in the reader `read` into the block `store`, splitting it into
+/// blocks with the `chunker` and recording `meta` on the file. Chunks that are
+/// already in the store are not written again. Data of at most `inline_limit`
+/// bytes is stored in the file node itself instead.
+pub async fn import_file<R, S>(
+    mut read: R,
+    chunker: &dyn Chunker,
+    meta: Metadata,
+    inline_limit: usize,
+    store: &S,
+) -> Result<(File, Cid, ImportStats)>
+where
+    R: Read,
+    S: BlockStore + ?Sized,
+{
+    let head = read_head(&mut read, inline_limit)?;
+    if !head.is_empty() && head.len() <= inline_limit {
+        let len = head.len() as u64;
+        let mut file = File::new_inline(head, Some(chunker.to_string()))?;
+        file.meta = meta;
+        // The data lives in the node, so it is only new if the node is.
+        let exists = store.has(&store::node_cid(&file)?).await?;
+        let stats = ImportStats {
+            total_bytes: len,
+            unique_bytes: if exists { 0 } else { len },
+            ..ImportStats::default()
+        };
+        let cid = store::put_node(store, &file).await?;
+        return Ok((file, cid, stats));
+    }
+
+    let read = head.as_slice().chain(read);
+    let (data, stats) = import_chunks(read, 0, chunker, store, &HashSet::new()).await?;
+    let data = balance(store, data).await?;
+    let mut file = File::new(data, Some(chunker.to_string()))?;
+    file.meta = meta;
+    let cid = store::put_node(store, &file).await?;
+    Ok((file, cid, stats))
+}
+
+/// Read just enough of `read` to tell whether it fits in `inline_limit` bytes. If
+/// the result is longer, it has to be put back in front of the rest of `read`.
+fn read_head<R: Read>(read: &mut R, inline_limit: usize) -> Result<Vec<u8>> {
+    let mut head = Vec::new();
+    read.take((inline_limit as u64).saturating_add(1))
+        .read_to_end(&mut head)?;
+    Ok(head)
+}
+
+/// Import the regular file `f` like [`import_file`](import_file), but find its holes
+/// with `SEEK_DATA` and `SEEK_HOLE` and record them as such, instead of storing zeros.
+pub async fn import_sparse_file<S>(
+    f: &std::fs::File,
+    chunker: &dyn Chunker,
+    meta: Metadata,
+    inline_limit: usize,
+    store: &S,
+) -> Result<(File, Cid, ImportStats)>
+where
+    S: BlockStore + ?Sized,
+{
+    let size = f.metadata()?.len();
+    if size <= inline_limit as u64 {
+        return import_file(f, chunker, meta, inline_limit, store).await;
+    }
+    let mut data = Vec::new();
+    let mut stats = ImportStats::default();
+    let mut pos = 0;
+    while pos < size {
+        let start = seek(f, pos, libc::SEEK_DATA)?.unwrap_or(size).min(size);
+        if start > pos {
+            data.push(FileDataEntry::hole(pos, start));
+        }
+        if start == size {
+            break;
+        }
+
+        // Filesystems without hole support report the whole file as one data region.
+        let end = seek(f, start, libc::SEEK_HOLE)?.unwrap_or(size).min(size);
+        let mut region = f;
+        region.seek(SeekFrom::Start(start))?;
+        let (entries, s) = import_chunks(
+            region.take(end - start),
+            start,
+            chunker,
+            store,
+            &HashSet::new(),
+        )
+        .await?;
+        ensure!(
+            s.total_bytes > 0,
+            "File changed while it was being imported"
+        );
+        data.extend(entries);
+        pos = start + s.total_bytes;
+        stats += s;
+    }
+
+    let data = balance(store, data).await?;
+    let mut file = File::new(data, Some(chunker.to_string()))?;
+    file.meta = meta;
+    let cid = store::put_node(store, &file).await?;
+    Ok((file, cid, stats))
+}
+
+/// `lseek` to the next data or hole from `pos`, returning `None` if there is no more data.
+fn seek(f: &std::fs::File, pos: u64, whence: libc::c_int) -> Result<Option<u64>> {
+    // SAFETY: the descriptor is owned by `f`, which outlives the call.
+    let ret = unsafe { libc::lseek(f.as_raw_fd(), pos.try_into()?, whence) };
+    if ret < 0 {
+        let e = std::io::Error::last_os_error();
+        return match e.raw_os_error() {
+            Some(libc::ENXIO) => Ok(None),
+            _ => Err(e.into()),
+        };
+    }
+    Ok(Some(ret.try_into()?))
+}
+
+/// Import a new version of the file `cid` from `read`, splitting it with the same
+/// chunker as the original so that unchanged regions produce the same blocks.
+/// The new version links back to `cid` as its previous version, and keeps its
+/// [`Metadata`](Metadata). Like [`import_file`](import_file), data of at most
+/// `inline_limit` bytes is stored in the node itself. Returns the new file along
+/// with how many of its chunks were reused from the original, out of how many it has.
+pub async fn update_file<R, S>(
+    cid: &Cid,
+    mut read: R,
+    author: Option<String>,
+    inline_limit: usize,
+    store: &S,
+) -> Result<(File, Cid, usize, usize)>
+where
+    R: Read,
+    S: BlockStore + ?Sized,
+{
+    let old = get_file(store, cid).await?;
+    let chunker = chunker::parse(old.chunker().unwrap_or(chunker::DEFAULT))?;
+    let known = leaves(store, &old.data, 0, old.size)
+        .await?
+        .into_iter()
+        .filter_map(|entry| entry.link.map(|link| *link.cid()))
+        .collect::<HashSet<Cid>>();
+
+    let head = read_head(&mut read, inline_limit)?;
+    let (mut file, reused, chunks) = if !head.is_empty() && head.len() <= inline_limit {
+        (File::new_inline(head, Some(chunker.to_string()))?, 0, 0)
+    } else {
+        let read = head.as_slice().chain(read);
+        let (data, stats) = import_chunks(read, 0, &*chunker, store, &known).await?;
+        let reused = data
+            .iter()
+            .filter_map(|entry| entry.link.as_ref())
+            .filter(|link| known.contains(link.cid()))
+            .count();
+        let data = balance(store, data).await?;
+        let file = File::new(data, Some(chunker.to_string()))?;
+        (file, reused, stats.chunks.try_into()?)
+    };
+    file.previous = Some(Link::new(*cid));
+    file.timestamp = Some(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs());
+    file.author = author;
+    file.meta = old.meta;
+
+    let cid = store::put_node(store, &file).await?;
+    Ok((file, cid, reused, chunks))
+}
+
+/// Walk the chain of previous versions of the file `cid`, newest first.
+pub async fn history<S>(store: &S, cid: &Cid) -> Result<Vec<(Cid, File)>>
+where
+    S: BlockStore + ?Sized,
+{
+    let mut versions = Vec::new();
+    let mut next = Some(*cid);
+    while let Some(cid) = next {
+        let file = get_file(store, &cid).await?;
+        next = file.previous().copied();
+        versions.push((cid, file));
+    }
+    Ok(versions)
+}
+
+/// Split `read` into blocks with the `chunker` and store any that aren't in `known`
+/// or already in the `store`.
+async fn import_chunks<R, S>(
+    read: R,
+    offset: u64,
+    chunker: &dyn Chunker,
+    store: &S,
+    known: &HashSet<Cid>,
+) -> Result<(Vec<FileDataEntry>, ImportStats)>
+where
+    R: Read,
+    S: BlockStore + ?Sized,
+{
+    let mut data = Vec::new();
+    let mut stats = ImportStats::default();
+    for chunk in Chunks::new(chunker, read) {
+        let chunk = chunk?;
+        let len = chunk.len();
+        let len64 = TryInto::<u64>::try_into(len)?;
+
+        let cid = store::cid_for(store::RAW, &chunk);
+        if known.contains(&cid) || store.has(&cid).await? {
+            stats.existing_chunks += 1;
+        } else {
+            store.put(&cid, chunk.into()).await?;
+            stats.unique_bytes += len64;
+        }
+
+        data.push(FileDataEntry::new(offset + stats.total_bytes, len, cid)?);
+        stats.total_bytes += len64;
+        stats.chunks += 1;
+    }
+    Ok((data, stats))
+}
+
+/// Fetch and decode the [`File`](File) root node `cid` from the `store`, rejecting
+/// it if it doesn't [`validate`](File::validate).
+pub async fn get_file<S: BlockStore + ?Sized>(store: &S, cid: &Cid) -> Result<File> {
+    let file: File = store::get_node(store, cid).await?;
+    file.validate()?;
+    Ok(file)
+}
+
+/// Read up to `len` bytes of the file `cid` starting at `offset`, fetching only the
+/// blocks that overlap that range. The result is truncated at the end of the file.
+pub async fn read_range<S>(store: &S, cid: &Cid, offset: u64, len: u64) -> Result<Vec<u8>>
+where
+    S: BlockStore + ?Sized,
+{
+    let file = get_file(store, cid).await?;
+    let start = offset.min(file.size);
+    let end = offset.saturating_add(len).min(file.size);
+
+    if let Some(data) = &file.inline {
+        return Ok(data[start.try_into()?..end.try_into()?].to_vec());
+    }
+
+    let mut out = Vec::with_capacity((end - start).try_into()?);
+    for entry in leaves(store, &file.data, start, end).await? {
+        let from: usize = (start.max(entry.bounds.0) - entry.bounds.0).try_into()?;
+        let to: usize = (end.min(entry.bounds.1) - entry.bounds.0).try_into()?;
+        match get_chunk(store, &entry).await? {
+            Some(data) => out.extend_from_slice(&data[from..to]),
+            None => out.resize(out.len() + (to - from), 0),
+        }
+    }
+    Ok(out)
+}
+
+/// Fetch the block for `entry`, checking that it covers its bounds, or `None` for a hole.
+async fn get_chunk<S>(store: &S, entry: &FileDataEntry) -> Result<Option<Bytes>>
+where
+    S: BlockStore + ?Sized,
+{
+    let link = match &entry.link {
+        None => return Ok(None),
+        Some(link) => link,
+    };
+    let data = store::get_block(store, link.cid()).await?;
+    ensure!(
+        data.len() as u64 == entry.bounds.1 - entry.bounds.0,
+        "Block `{}` is {} bytes, expected {}",
+        link.cid(),
+        data.len(),
+        entry.bounds.1 - entry.bounds.0
+    );
+    Ok(Some(data))
+}
+
+/// Write the data under `entries` out to `f`, seeking over holes so that they stay sparse.
+fn write_sparse<'a, S>(
+    store: &'a S,
+    entries: &'a [FileDataEntry],
+    f: &'a mut std::fs::File,
+) -> BoxFuture<'a, Result<()>>
+where
+    S: BlockStore + ?Sized,
+{
+    async move {
+        for entry in entries {
+            if let Some(cid) = entry.node() {
+                let children = get_children(store, cid, &entry.bounds).await?;
+                write_sparse(store, &children, f).await?;
+                continue;
+            }
+            match get_chunk(store, entry).await? {
+                Some(data) => f.write_all(&data)?,
+                None => {
+                    f.seek(SeekFrom::Start(entry.bounds.1))?;
+                }
+            }
+        }
+        Ok(())
+    }
+    .boxed()
+}
+
+/// Fetch the node `cid` from the `store`, decoding it according to its `type`.
+pub async fn get_node<S: BlockStore + ?Sized>(store: &S, cid: &Cid) -> Result<Node> {
+    let data = store::get_block(store, cid).await?;
+    let ipld: Ipld = DagCborCodec.decode(&data)?;
+    let ty = match &ipld {
+        Ipld::Map(map) => match map.get("type") {
+            Some(Ipld::String(ty)) => ty.as_str(),
+            _ => bail!("Node `{}` has no type", cid),
+        },
+        _ => bail!("Node `{}` is not a map", cid),
+    };
+    match ty {
+        FILE_TYPE => {
+            let file: File = DagCborCodec.decode(&data)?;
+            file.validate()?;
+            Ok(Node::File(file))
+        }
+        DIR_TYPE => Ok(Node::Directory(DagCborCodec.decode(&data)?)),
+        HAMT_TYPE => Ok(Node::ShardedDirectory(DagCborCodec.decode(&data)?)),
+        SYMLINK_TYPE => Ok(Node::Symlink(DagCborCodec.decode(&data)?)),
+        ty => bail!("Node `{}` has unknown type `{}`", cid, ty),
+    }
+}
+
+/// Store a directory node holding `entries`, sharding it into a [`Hamt`](Hamt) if it
+/// has more than [`SHARD_THRESHOLD`](SHARD_THRESHOLD) entries.
+pub async fn put_dir<S>(
+    store: &S,
+    entries: BTreeMap<String, Cid>,
+    meta: Metadata,
+    hardlinks: HardLinks,
+) -> Result<Cid>
+where
+    S: BlockStore + ?Sized,
+{
+    if entries.len() <= SHARD_THRESHOLD {
+        return store::put_node(store, &Directory::new(entries, meta, hardlinks)?).await;
+    }
+
+    let mut hamt = Hamt::new(store, hamt::DEFAULT_BIT_WIDTH, hamt::DEFAULT_BUCKET_SIZE)?;
+    hamt.set_metadata(meta);
+    for name in hardlinks.keys() {
+        ensure!(
+            entries.contains_key(name),
+            "Hard link to missing entry `{}`",
+            name
+        );
+    }
+    hamt.set_hardlinks(hardlinks);
+    for (name, cid) in entries {
+        ensure!(valid_name(&name), "Invalid directory entry name `{}`", name);
+        hamt.insert(name, cid).await?;
+    }
+    Ok(hamt.flush().await?.1)
+}
+
+/// Load every entry of the directory `node`, whether or not it is sharded.
+pub async fn list_dir<S>(store: &S, node: &Node) -> Result<BTreeMap<String, Cid>>
+where
+    S: BlockStore + ?Sized,
+{
+    match node {
+        Node::File(_) | Node::Symlink(_) => bail!("Not a directory"),
+        Node::Directory(dir) => Ok(dir
+            .entries()
+            .map(|(name, cid)| (name.to_string(), *cid))
+            .collect()),
+        Node::ShardedDirectory(dir) => Ok(Hamt::load(store, dir)?
+            .entries()
+            .await?
+            .into_iter()
+            .collect()),
+    }
+}
+
+/// Import the symbolic link at `path` as a [`Symlink`](Symlink), without following it.
+pub async fn import_symlink<S>(path: &Path, xattrs: &XattrFilter, store: &S) -> Result<Cid>
+where
+    S: BlockStore + ?Sized,
+{
+    let target = std::fs::read_link(path)
+        .map_err(|e| anyhow!("Couldn't read link `{}`: {}", path.display(), e))?;
+    let target = target
+        .into_os_string()
+        .into_string()
+        .map_err(|target| anyhow!("Non UTF-8 link target {:?} in `{}`", target, path.display()))?;
+    let meta = Metadata::from_path(path, false, xattrs)?;
+    store::put_node(store, &Symlink::new(target, meta)).await
+}
+
+/// Recursively import the directory tree rooted at `path` into the block `store`,
+/// importing each regular file with [`import_sparse_file`](import_sparse_file).
+///
+/// Symbolic links are recorded with [`import_symlink`](import_symlink), unless
+/// `opts.dereference` is set, in which case whatever they point to is imported instead,
+/// failing if a link leads back to a directory that contains it.
+/// Hard links to a file already imported share its node rather than being read again,
+/// and every link to such a file is recorded in the same [`HardLinks`](HardLinks) group.
+/// FIFOs, sockets and devices are skipped with a warning.
+pub fn import_dir<'a, S>(
+    path: &'a Path,
+    chunker: &'a dyn Chunker,
+    opts: &'a ImportOptions,
+    store: &'a S,
+) -> BoxFuture<'a, Result<(Cid, ImportStats)>>
+where
+    S: BlockStore + ?Sized,
+{
+    async move {
+        let mut inodes = HashMap::new();
+        import_tree(path, chunker, opts, &mut inodes, &mut HashSet::new(), store).await
+    }
+    .boxed()
+}
+
+/// [`import_dir`](import_dir), remembering the node and hard link group of every
+/// multiply linked file in `inodes`, and the directories from the root down to
+/// `path` in `ancestors`, both by device and inode number.
+fn import_tree<'a, S>(
+    path: &'a Path,
+    chunker: &'a dyn Chunker,
+    opts: &'a ImportOptions,
+    inodes: &'a mut HashMap<(u64, u64), (Cid, u64)>,
+    ancestors: &'a mut HashSet<(u64, u64)>,
+    store: &'a S,
+) -> BoxFuture<'a, Result<(Cid, ImportStats)>>
+where
+    S: BlockStore + ?Sized,
+{
+    async move {
+        // Only reachable through a dereferenced symlink, since the tree itself can't loop.
+        let dir = std::fs::metadata(path)?;
+        let inode = (dir.dev(), dir.ino());
+        if !ancestors.insert(inode) {
+            bail!("Symlink cycle at `{}`", path.display());
+        }
+
+        let mut entries = BTreeMap::new();
+        let mut hardlinks = HardLinks::new();
+        let mut stats = ImportStats::default();
+        // In name order, so that hard link groups are numbered the same every time.
+        let mut dir_entries = std::fs::read_dir(path)?.collect::<Result<Vec<_>, _>>()?;
+        dir_entries.sort_by_key(|entry| entry.file_name());
+        for entry in dir_entries {
+            let name = entry.file_name().into_string().map_err(|name| {
+                anyhow!("Non UTF-8 file name {:?} in `{}`", name, path.display())
+            })?;
+            let path = entry.path();
+
+            let file_type = if opts.dereference {
+                std::fs::metadata(&path)?.file_type()
+            } else {
+                entry.file_type()?
+            };
+            let cid = if file_type.is_dir() {
+                let (cid, s) = import_tree(&path, chunker, opts, inodes, ancestors, store).await?;
+                stats += s;
+                cid
+            } else if file_type.is_symlink() {
+                import_symlink(&path, &opts.xattrs, store).await?
+            } else if !file_type.is_file() {
+                // Opening a FIFO would wait for a writer, and devices and sockets have
+                // no contents to speak of.
+                eprintln!("Skipping `{}`, which is not a regular file", path.display());
+                continue;
+            } else {
+                let f = std::fs::File::open(&path)
+                    .map_err(|e| anyhow!("Couldn't open `{}`: {}", path.display(), e))?;
+                let fs_meta = f.metadata()?;
+                let inode = (fs_meta.dev(), fs_meta.ino());
+                match inodes.get(&inode) {
+                    Some(&(cid, group)) => {
+                        hardlinks.insert(name.clone(), group);
+                        cid
+                    }
+                    None => {
+                        let meta = Metadata::from_path(&path, true, &opts.xattrs)?;
+                        let (_, cid, s) =
+                            import_sparse_file(&f, chunker, meta, opts.inline_limit, store).await?;
+                        if fs_meta.nlink() > 1 {
+                            let group = inodes.len() as u64;
+                            inodes.insert(inode, (cid, group));
+                            hardlinks.insert(name.clone(), group);
+                        }
+                        stats += s;
+                        cid
+                    }
+                }
+            };
+            entries.insert(name, cid);
+        }
+        ancestors.remove(&inode);
+
+        let meta = Metadata::from_path(path, true, &opts.xattrs)?;
+        Ok((put_dir(store, entries, meta, hardlinks).await?, stats))
+    }
+    .boxed()
+}
+
+/// Write the node `cid` out to `path`, recreating the whole tree if it is a
+/// [`Directory`](Directory), and restoring each node's [`Metadata`](Metadata).
+pub fn export<'a, S>(
+    store: &'a Arc<S>,
+    cid: &'a Cid,
+    path: &'a Path,
+    opts: &'a ExportOptions,
+) -> BoxFuture<'a, Result<()>>
+where
+    S: BlockStore + ?Sized + 'static,
+{
+    async move { export_tree(store, cid, path, opts, None, &mut HashMap::new()).await }.boxed()
+}
+
+/// [`export`](export), for a node in the hard link group `group`, if any. Remembers
+/// where the first file of each group was written in `written`, so later ones can
+/// be linked to it. Group ids only mean something within one import, and a merge
+/// can bring several imports together, so files are only linked if their nodes
+/// are the same as well.
+fn export_tree<'a, S>(
+    store: &'a Arc<S>,
+    cid: &'a Cid,
+    path: &'a Path,
+    opts: &'a ExportOptions,
+    group: Option<u64>,
+    written: &'a mut HashMap<(u64, Cid), PathBuf>,
+) -> BoxFuture<'a, Result<()>>
+where
+    S: BlockStore + ?Sized + 'static,
+{
+    async move {
+        let group = group.filter(|_| opts.hardlinks);
+        if let Some(first) = group.and_then(|group| written.get(&(group, *cid))) {
+            // The metadata was restored along with the first link.
+            return std::fs::hard_link(first, path)
+                .map_err(|e| anyhow!("Couldn't link `{}`: {}", path.display(), e));
+        }
+
+        let node = get_node(&**store, cid).await?;
+        match &node {
+            Node::File(file) => {
+                let mut f = std::fs::File::create(path)
+                    .map_err(|e| anyhow!("Couldn't create `{}`: {}", path.display(), e))?;
+                match &file.inline {
+                    Some(data) => f.write_all(data)?,
+                    None => {
+                        write_sparse(&**store, &file.data, &mut f).await?;
+                        // A trailing hole has to be made by extending the file.
+                        f.set_len(file.size)?;
+                    }
+                }
+            }
+            Node::Symlink(link) => {
+                std::os::unix::fs::symlink(link.target(), path)
+                    .map_err(|e| anyhow!("Couldn't create `{}`: {}", path.display(), e))?;
+            }
+            Node::Directory(_) | Node::ShardedDirectory(_) => {
+                std::fs::create_dir_all(path)
+                    .map_err(|e| anyhow!("Couldn't create `{}`: {}", path.display(), e))?;
+                for (name, cid) in list_dir(&**store, &node).await? {
+                    // Names are checked on construction, but the node may not have come from us.
+                    ensure!(valid_name(&name), "Invalid directory entry name `{}`", name);
+                    let group = node.hard_link(&name);
+                    export_tree(store, &cid, &path.join(name), opts, group, written).await?;
+                }
+            }
+        }
+
+        // Directories are done last, after writing their entries has touched them.
+        node.metadata().restore(path, opts)?;
+        if let Some(group) = group {
+            written.insert((group, *cid), path.to_path_buf());
+        }
+        Ok(())
+    }
+    .boxed()
+}
+
+/// The entries of one [`File`](File) or [`FileData`](FileData) node.
+type Level = Arc<[FileDataEntry]>;
+
+enum ReaderState {
+    /// Nothing in flight and nothing buffered.
+    Idle,
+    /// Waiting on the root [`File`](File) node.
+    Resolving(BoxFuture<'static, Result<File>>),
+    /// Waiting on the leaf covering an offset, its block, and the level it is in.
+    Fetching(
+        u64,
+        BoxFuture<'static, Result<(FileDataEntry, Option<Bytes>, Level)>>,
+    ),
+    /// A leaf and the contents of its block, or `None` for a hole.
+    Buffered(FileDataEntry, Option<Bytes>),
+}
+
+/// Streams the contents of a [`File`](File) out of a block store, one block at a time.
+///
+/// The root node is fetched lazily on the first read or seek. Blocks are fetched
+/// in the order they are read, and seeking only refetches a block if the new
+/// position falls outside of the one currently buffered. Finding a block takes
+/// one fetch per level of [`FileData`](FileData) nodes above it, or none if it
+/// shares a node with the block read last.
+pub struct FileReader<S: ?Sized> {
+    cid: Cid,
+    store: Arc<S>,
+    file: Option<File>,
+    /// The entries of the root node, and of the node holding the last leaf read.
+    root: Level,
+    level: Level,
+    pos: u64,
+    state: ReaderState,
+}
+
+impl<S: BlockStore + ?Sized + 'static> FileReader<S> {
+    pub fn new(cid: Cid, store: Arc<S>) -> Self {
+        FileReader {
+            cid,
+            store,
+            file: None,
+            root: Vec::new().into(),
+            level: Vec::new().into(),
+            pos: 0,
+            state: ReaderState::Idle,
+        }
+    }
+
+    /// Drive the fetch of the root node, returning it once it has been decoded.
+    fn poll_file(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<&File>> {
+        if self.file.is_none() {
+            if !matches!(self.state, ReaderState::Resolving(_)) {
+                let store = self.store.clone();
+                let cid = self.cid;
+                self.state =
+                    ReaderState::Resolving(async move { get_file(&*store, &cid).await }.boxed());
+            }
+
+            let file = match &mut self.state {
+                ReaderState::Resolving(fut) => ready!(fut.poll_unpin(cx)),
+                _ => unreachable!(),
+            };
+            self.state = ReaderState::Idle;
+            let file = file.map_err(to_io_error)?;
+            self.root = file.data.clone().into();
+            self.level = self.root.clone();
+            self.file = Some(file);
+        }
+
+        Poll::Ready(Ok(self.file.as_ref().unwrap()))
+    }
+}
+
+impl<S: BlockStore + ?Sized + 'static> AsyncRead for FileReader<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let pos = this.pos;
+        {
+            let file = ready!(this.poll_file(cx))?;
+            if buf.is_empty() || pos >= file.size {
+                return Poll::Ready(Ok(0));
+            }
+            if let Some(data) = &file.inline {
+                let offset: usize = pos.try_into().map_err(to_io_error)?;
+                let n = (&data[offset..]).read(buf)?;
+                this.pos += n as u64;
+                return Poll::Ready(Ok(n));
+            }
+        }
+
+        loop {
+            match &mut this.state {
+                ReaderState::Buffered(entry, data)
+                    if entry.bounds.0 <= pos && pos < entry.bounds.1 =>
+                {
+                    let offset: usize = (pos - entry.bounds.0).try_into().map_err(to_io_error)?;
+                    let n = match data {
+                        Some(data) => (&data[offset..]).read(buf)?,
+                        // Holes read as zeros.
+                        None => {
+                            let len = (entry.bounds.1 - pos).try_into().unwrap_or(usize::MAX);
+                            let n = buf.len().min(len);
+                            buf[..n].fill(0);
+                            n
+                        }
+                    };
+                    this.pos += n as u64;
+                    return Poll::Ready(Ok(n));
+                }
+                ReaderState::Fetching(at, fut) if *at == pos => {
+                    let leaf = ready!(fut.poll_unpin(cx));
+                    this.state = ReaderState::Idle;
+                    let (entry, data, level) = leaf.map_err(to_io_error)?;
+                    this.level = level;
+                    this.state = ReaderState::Buffered(entry, data);
+                }
+                _ => {
+                    // The next leaf is usually under the same node as the last one.
+                    let level = if overlapping(&this.level, pos, pos + 1).is_empty() {
+                        this.root.clone()
+                    } else {
+                        this.level.clone()
+                    };
+                    let store = this.store.clone();
+                    this.state = ReaderState::Fetching(
+                        pos,
+                        async move {
+                            let (entry, level) = locate(&*store, level, pos).await?;
+                            let data = get_chunk(&*store, &entry).await?;
+                            Ok((entry, data, level))
+                        }
+                        .boxed(),
+                    );
+                }
+            }
+        }
+    }
+}
+
+impl<S: BlockStore + ?Sized + 'static> AsyncSeek for FileReader<S> {
+    fn poll_seek(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        pos: SeekFrom,
+    ) -> Poll<std::io::Result<u64>> {
+        let this = self.get_mut();
+        let size = ready!(this.poll_file(cx))?.size;
+
+        let pos = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(n) => checked_add_signed(size, n),
+            SeekFrom::Current(n) => checked_add_signed(this.pos, n),
+        };
+
+        match pos {
+            None => Poll::Ready(Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            ))),
+            Some(pos) => {
+                this.pos = pos;
+                Poll::Ready(Ok(pos))
+            }
+        }
+    }
+}
+
+/// Descend from `level` to the leaf covering `pos`, returning it along with the
+/// level it was found in.
+async fn locate<S>(store: &S, mut level: Level, pos: u64) -> Result<(FileDataEntry, Level)>
+where
+    S: BlockStore + ?Sized,
+{
+    loop {
+        let entry = overlapping(&level, pos, pos + 1)
+            .first()
+            .cloned()
+            .ok_or_else(|| anyhow!("No file data at offset {}", pos))?;
+        match entry.node() {
+            Some(cid) => level = get_children(store, cid, &entry.bounds).await?.into(),
+            None => return Ok((entry, level)),
+        }
+    }
+}
+
+fn checked_add_signed(base: u64, offset: i64) -> Option<u64> {
+    if offset >= 0 {
+        base.checked_add(offset as u64)
+    } else {
+        base.checked_sub(offset.unsigned_abs())
+    }
+}
+
+fn to_io_error<E: Display>(e: E) -> std::io::Error {
+    std::io::Error::other(format!("{}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunker::FixedChunker;
+    use crate::store::MemoryBlockStore;
+
+    use futures::AsyncReadExt;
+
+    use std::os::unix::fs::FileExt;
+
+    /// `len` bytes of data that doesn't repeat at any chunk size used here.
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+    }
+
+    /// Import `data` in fixed chunks of `size` bytes, never inline.
+    async fn import<S>(store: &S, data: &[u8], size: usize) -> (File, Cid, ImportStats)
+    where
+        S: BlockStore + ?Sized,
+    {
+        let chunker = FixedChunker::new(size).unwrap();
+        import_file(data, &chunker, Metadata::default(), 0, store)
+            .await
+            .unwrap()
+    }
+
+    fn bounds(file: &File) -> Vec<(u64, u64)> {
+        file.data
+            .iter()
+            .map(|entry| (entry.bounds.0, entry.bounds.1))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn import_and_read_back() {
+        let store = Arc::new(MemoryBlockStore::new());
+        let data = data(10_000);
+        let (file, cid, _) = import(&*store, &data, 1024).await;
+        assert_eq!(file.size(), data.len() as u64);
+
+        let mut out = Vec::new();
+        FileReader::new(cid, store.clone())
+            .read_to_end(&mut out)
+            .await
+            .unwrap();
+        assert_eq!(out, data);
+        assert_eq!(get_file(&*store, &cid).await.unwrap(), file);
+    }
+
+    #[test]
+    fn file_new_sorts_entries() {
+        let entry = |start: u64, len: usize| {
+            let cid = store::cid_for(store::RAW, &start.to_be_bytes());
+            FileDataEntry::new(start, len, cid).unwrap()
+        };
+        let file = File::new(vec![entry(20, 5), entry(0, 10), entry(10, 10)], None).unwrap();
+        assert_eq!(bounds(&file), [(0, 10), (10, 20), (20, 25)]);
+        assert_eq!(file.size(), 25);
+
+        // Errors point at the entries as they were passed in.
+        let err = File::new(vec![entry(20, 5), entry(0, 10)], None).unwrap_err();
+        assert_eq!(
+            err,
+            UnixfsError::Gap {
+                index: 1,
+                end: 10,
+                next: 20
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn invalid_files_are_rejected() {
+        let entry = |start: u64, len: usize| {
+            let cid = store::cid_for(store::RAW, &start.to_be_bytes());
+            FileDataEntry::new(start, len, cid).unwrap()
+        };
+        let invalid = |data| File::new(data, None).unwrap_err();
+        assert_eq!(
+            invalid(vec![entry(5, 10), entry(0, 10)]),
+            UnixfsError::Overlap {
+                index: 1,
+                end: 10,
+                next: 5
+            }
+        );
+        assert_eq!(
+            invalid(vec![entry(0, 10), FileDataEntry::hole(10, 10)]),
+            UnixfsError::EmptyEntry {
+                index: 1,
+                start: 10,
+                end: 10
+            }
+        );
+        assert_eq!(
+            invalid(vec![entry(5, 5)]),
+            UnixfsError::NotStartingAtZero { start: 5 }
+        );
+
+        let file = File::new(vec![entry(0, 10)], None).unwrap();
+        let mut wrong_size = file.clone();
+        wrong_size.size = 20;
+        assert_eq!(
+            wrong_size.validate(),
+            Err(UnixfsError::SizeMismatch { size: 20, end: 10 })
+        );
+        let mut inline = file;
+        inline.inline = Some(b"0123456789".to_vec().into());
+        assert_eq!(
+            inline.validate(),
+            Err(UnixfsError::InlineWithEntries { entries: 1 })
+        );
+
+        // Nodes are checked whenever they are fetched.
+        let store = MemoryBlockStore::new();
+        for node in [wrong_size, inline] {
+            let cid = store::put_node(&store, &node).await.unwrap();
+            let err = get_file(&store, &cid).await.unwrap_err();
+            assert!(err.downcast_ref::<UnixfsError>().is_some(), "{}", err);
+            let err = get_node(&store, &cid).await.unwrap_err();
+            assert!(err.downcast_ref::<UnixfsError>().is_some(), "{}", err);
+        }
+        let not_file_data = FileData {
+            data: vec![entry(0, 10)],
+            ty: FILE_TYPE.to_string(),
+        };
+        let cid = store::put_node(&store, &not_file_data).await.unwrap();
+        let err = get_children(&store, &cid, &FileDataBounds(0, 10))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("instead of `filedata`"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn chunk_boundaries() {
+        let store = MemoryBlockStore::new();
+        let data = data(2500);
+        let (file, cid, stats) = import(&store, &data, 1000).await;
+        assert_eq!(bounds(&file), [(0, 1000), (1000, 2000), (2000, 2500)]);
+        assert_eq!(stats.chunks, 3);
+
+        // Ranges that start on, end on, straddle and run past chunk boundaries.
+        for (offset, len) in [(0, 1000), (1000, 1000), (990, 20), (1999, 2), (2400, 500)] {
+            let end = (offset + len).min(data.len() as u64) as usize;
+            let got = read_range(&store, &cid, offset, len).await.unwrap();
+            assert_eq!(got, data[offset as usize..end]);
+        }
+    }
+
+    #[tokio::test]
+    async fn update_keeps_metadata() {
+        let store = MemoryBlockStore::new();
+        let meta = Metadata {
+            mode: Some(0o640),
+            mtime: Some(1_600_000_000),
+            mtime_nsecs: Some(500),
+            uid: Some(1000),
+            gid: Some(100),
+            xattrs: Some([("user.tag".to_string(), b"v1".to_vec().into())].into()),
+        };
+        let chunker = FixedChunker::new(1000).unwrap();
+        let mut data = data(5000);
+        let (_, cid, _) = import_file(&data[..], &chunker, meta.clone(), 0, &store)
+            .await
+            .unwrap();
+
+        data[2500] ^= 1;
+        let (file, new, reused, chunks) =
+            update_file(&cid, &data[..], None, 0, &store).await.unwrap();
+        assert_eq!((reused, chunks), (4, 5));
+        assert_eq!(file.metadata(), &meta);
+        assert_eq!(get_file(&store, &new).await.unwrap().metadata(), &meta);
+        assert_eq!(file.previous(), Some(&cid));
+    }
+
+    #[tokio::test]
+    async fn update_stores_small_files_inline() {
+        let store = MemoryBlockStore::new();
+        let (_, cid, _) = import(&store, &data(5000), 1000).await;
+
+        let (file, small, _, chunks) = update_file(&cid, &b"small"[..], None, 1024, &store)
+            .await
+            .unwrap();
+        assert_eq!(file.inline.as_deref(), Some(&b"small"[..]));
+        assert!(file.data.is_empty());
+        assert_eq!(chunks, 0);
+
+        // And back out again, from an inline version.
+        let (file, _, reused, chunks) = update_file(&small, &data(5000)[..], None, 1024, &store)
+            .await
+            .unwrap();
+        assert!(file.inline.is_none());
+        assert_eq!((reused, chunks), (0, 5));
+    }
+
+    #[tokio::test]
+    async fn dereference_detects_cycles() {
+        let root = std::env::temp_dir().join(format!("tops-cycle-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("a/b")).unwrap();
+        std::os::unix::fs::symlink("../../a", root.join("a/b/up")).unwrap();
+        // A second link to the same directory is fine, as long as it isn't an ancestor.
+        std::os::unix::fs::symlink("a/b", root.join("b")).unwrap();
+
+        let store = MemoryBlockStore::new();
+        let chunker = FixedChunker::new(1000).unwrap();
+        let mut opts = ImportOptions::default();
+        assert!(import_dir(&root, &chunker, &opts, &store).await.is_ok());
+
+        opts.dereference = true;
+        let err = import_dir(&root, &chunker, &opts, &store)
+            .await
+            .unwrap_err();
+        std::fs::remove_dir_all(&root).unwrap();
+        assert!(err.to_string().contains("Symlink cycle"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn hard_link_groups_are_kept_apart() {
+        use std::os::unix::fs::MetadataExt;
+
+        let root = std::env::temp_dir().join(format!("tops-links-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let (src, out) = (root.join("src"), root.join("out"));
+        std::fs::create_dir_all(&src).unwrap();
+        // Two groups of links to files with the same contents and metadata, which
+        // are stored as a single node.
+        let mtime = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000);
+        for (name, link) in [("a", "b"), ("c", "d")] {
+            std::fs::write(src.join(name), b"same").unwrap();
+            std::fs::File::options()
+                .write(true)
+                .open(src.join(name))
+                .unwrap()
+                .set_modified(mtime)
+                .unwrap();
+            std::fs::hard_link(src.join(name), src.join(link)).unwrap();
+        }
+
+        let store = Arc::new(MemoryBlockStore::new());
+        let chunker = FixedChunker::new(1000).unwrap();
+        let (cid, _) = import_dir(&src, &chunker, &ImportOptions::default(), &*store)
+            .await
+            .unwrap();
+        let dir = get_node(&*store, &cid).await.unwrap();
+        let entries = list_dir(&*store, &dir).await.unwrap();
+        assert_eq!(entries["a"], entries["c"]);
+
+        let opts = ExportOptions {
+            owner: false,
+            hardlinks: true,
+            ..ExportOptions::default()
+        };
+        export(&store, &cid, &out, &opts).await.unwrap();
+        let ino = |name| std::fs::metadata(out.join(name)).unwrap().ino();
+        let (a, b, c, d) = (ino("a"), ino("b"), ino("c"), ino("d"));
+        std::fs::remove_dir_all(&root).unwrap();
+        assert_eq!(a, b);
+        assert_eq!(c, d);
+        assert_ne!(a, c);
+    }
+
+    #[tokio::test]
+    async fn special_files_are_skipped() {
+        let root = std::env::temp_dir().join(format!("tops-special-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("file"), b"data").unwrap();
+        let fifo = std::ffi::CString::new(root.join("fifo").as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) }, 0);
+        let _socket = std::os::unix::net::UnixListener::bind(root.join("socket")).unwrap();
+
+        let store = MemoryBlockStore::new();
+        let chunker = FixedChunker::new(1000).unwrap();
+        let res = import_dir(&root, &chunker, &ImportOptions::default(), &store).await;
+        std::fs::remove_dir_all(&root).unwrap();
+        let dir = get_node(&store, &res.unwrap().0).await.unwrap();
+        let entries = list_dir(&store, &dir).await.unwrap();
+        assert_eq!(entries.keys().collect::<Vec<_>>(), ["file"]);
+    }
+
+    #[tokio::test]
+    async fn holes_stay_sparse() {
+        const REGION: u64 = 1 << 16;
+        let root = std::env::temp_dir().join(format!("tops-holes-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        // Holes at the start, in the middle and at the end, around two data regions.
+        let path = root.join("sparse");
+        let f = std::fs::File::create(&path).unwrap();
+        let region = data(REGION as usize);
+        for start in [REGION, 3 * REGION] {
+            f.write_all_at(&region, start).unwrap();
+        }
+        f.set_len(5 * REGION).unwrap();
+        drop(f);
+
+        let store = Arc::new(MemoryBlockStore::new());
+        let chunker = FixedChunker::new(REGION as usize).unwrap();
+        let f = std::fs::File::open(&path).unwrap();
+        let (file, cid, stats) = import_sparse_file(&f, &chunker, Metadata::default(), 0, &*store)
+            .await
+            .unwrap();
+        let holes = file
+            .data
+            .iter()
+            .filter(|entry| entry.link.is_none())
+            .map(|entry| (entry.bounds.0, entry.bounds.1))
+            .collect::<Vec<_>>();
+        let expected = [(0, 1), (2, 3), (4, 5)].map(|(start, end)| (start * REGION, end * REGION));
+        assert_eq!(holes, expected);
+        assert_eq!(stats.total_bytes, 2 * REGION);
+
+        let mut expected = vec![0; 5 * REGION as usize];
+        for start in [REGION, 3 * REGION] {
+            expected[start as usize..(start + REGION) as usize].copy_from_slice(&region);
+        }
+        let got = read_range(&*store, &cid, 0, 5 * REGION).await.unwrap();
+        assert!(got == expected);
+        // Straddling the end of a hole.
+        let got = read_range(&*store, &cid, REGION - 10, 20).await.unwrap();
+        assert_eq!(
+            got,
+            expected[(REGION - 10) as usize..(REGION + 10) as usize]
+        );
+        let mut got = Vec::new();
+        FileReader::new(cid, store.clone())
+            .read_to_end(&mut got)
+            .await
+            .unwrap();
+        assert!(got == expected);
+
+        let out = root.join("out");
+        let opts = ExportOptions {
+            owner: false,
+            ..ExportOptions::default()
+        };
+        export(&store, &cid, &out, &opts).await.unwrap();
+        let meta = std::fs::metadata(&out).unwrap();
+        let contents = std::fs::read(&out).unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+        assert!(contents == expected);
+        assert_eq!(meta.len(), 5 * REGION);
+        // Only the data regions take up space, give or take a block.
+        assert!(
+            meta.blocks() * 512 <= 2 * REGION + 4096,
+            "{} blocks",
+            meta.blocks()
+        );
+    }
+
+    #[tokio::test]
+    async fn reimport_is_fully_deduplicated() {
+        let store = MemoryBlockStore::new();
+        let data = data(5000);
+        let (_, cid, stats) = import(&store, &data, 1000).await;
+        assert_eq!(stats.existing_chunks, 0);
+        assert_eq!(stats.dedup_ratio(), 1.0);
+        let blocks = store.len();
+
+        let (_, again, stats) = import(&store, &data, 1000).await;
+        assert_eq!(again, cid);
+        assert_eq!(store.len(), blocks);
+        assert_eq!(stats.existing_chunks, stats.chunks);
+        assert_eq!(stats.unique_bytes, 0);
+        assert_eq!(stats.dedup_ratio(), f64::INFINITY);
+
+        // Inline files have no chunks, but the same goes for their nodes.
+        let chunker = FixedChunker::new(1000).unwrap();
+        let small = || import_file(&data[..100], &chunker, Metadata::default(), 1024, &store);
+        let (_, _, stats) = small().await.unwrap();
+        assert_eq!(stats.unique_bytes, 100);
+        let (_, _, stats) = small().await.unwrap();
+        assert_eq!(stats.unique_bytes, 0);
+        assert_eq!(stats.dedup_ratio(), f64::INFINITY);
+    }
+}
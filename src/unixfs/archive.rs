@@ -0,0 +1,394 @@
+use anyhow::{anyhow, bail, ensure, Result};
+
+use futures::future::BoxFuture;
+use futures::io::AllowStdIo;
+use futures::FutureExt;
+
+use libipld::cid::Cid;
+
+use std::collections::BTreeMap;
+use std::io::prelude::*;
+use std::path::{Component, Path};
+use std::sync::Arc;
+
+use tar::{Archive, EntryType, Header};
+
+use super::{
+    get_node, import_file, join, list_dir, put_dir, valid_name, FileReader, ImportStats, Metadata,
+    Node, Symlink,
+};
+use crate::chunker::Chunker;
+use crate::store::{self, BlockStore};
+
+/// Tar header fields hold names up to this long; longer ones need a GNU extension entry.
+const NAME_LEN: usize = 100;
+const BLOCK_LEN: u64 = 512;
+
+/// Import the tar archive `read` as a tree of nodes, without extracting it to disk.
+///
+/// Regular files, directories, symlinks and hard links are imported along with
+/// their mode, mtime and ownership. Other kinds of entries, such as devices, are skipped.
+/// Directories that only appear as the parent of some entry get no metadata, and a
+/// path that is both a file and a directory is an error.
+pub async fn import_tar<R, S>(
+    read: R,
+    chunker: &dyn Chunker,
+    inline_limit: usize,
+    store: &S,
+) -> Result<(Cid, ImportStats)>
+where
+    R: Read,
+    S: BlockStore + ?Sized,
+{
+    let mut stats = ImportStats::default();
+    // Every non-directory entry by its path, and every directory, "" being the root.
+    let mut nodes = BTreeMap::new();
+    let mut dirs = BTreeMap::new();
+    dirs.insert(String::new(), Metadata::default());
+
+    let mut archive = Archive::new(read);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = normalize(&entry.path()?)?;
+        let header = entry.header();
+        let meta = Metadata {
+            mode: header.mode().ok(),
+            mtime: header.mtime().ok().and_then(|t| t.try_into().ok()),
+            mtime_nsecs: None,
+            uid: header.uid().ok().and_then(|id| id.try_into().ok()),
+            gid: header.gid().ok().and_then(|id| id.try_into().ok()),
+            xattrs: None,
+        };
+
+        match header.entry_type() {
+            EntryType::Directory => {
+                dirs.insert(path, meta);
+                continue;
+            }
+            _ if path.is_empty() => bail!("Tar entry with an empty path"),
+            // The tar crate fills in the holes of sparse files as they are read.
+            EntryType::Regular | EntryType::Continuous | EntryType::GNUSparse => {
+                let (_, cid, s) =
+                    import_file(&mut entry, chunker, meta, inline_limit, store).await?;
+                stats += s;
+                nodes.insert(path, cid);
+            }
+            EntryType::Symlink => {
+                let target = link_name(&entry)?;
+                let cid = store::put_node(store, &Symlink::new(target, meta)).await?;
+                nodes.insert(path, cid);
+            }
+            EntryType::Link => {
+                let target = normalize(Path::new(&link_name(&entry)?))?;
+                let cid = *nodes
+                    .get(&target)
+                    .ok_or_else(|| anyhow!("Hard link `{}` to unknown entry `{}`", path, target))?;
+                nodes.insert(path, cid);
+            }
+            _ => continue,
+        }
+    }
+
+    // Make sure every entry's ancestors exist, even if the archive never mentioned them.
+    let paths = nodes
+        .keys()
+        .chain(dirs.keys())
+        .cloned()
+        .collect::<Vec<String>>();
+    for path in paths {
+        let mut path = path.as_str();
+        while let Some((parent, _)) = path.rsplit_once('/') {
+            dirs.entry(parent.to_string()).or_default();
+            path = parent;
+        }
+    }
+    if let Some(path) = nodes.keys().find(|path| dirs.contains_key(*path)) {
+        bail!("Tar entry `{}` is both a file and a directory", path);
+    }
+
+    let mut children: BTreeMap<String, BTreeMap<String, Cid>> = BTreeMap::new();
+    for (path, cid) in nodes {
+        let (parent, name) = split(&path);
+        children.entry(parent).or_default().insert(name, cid);
+    }
+
+    // Build directories deepest first, so each one's children exist before it does.
+    let mut dirs = dirs.into_iter().collect::<Vec<(String, Metadata)>>();
+    dirs.sort_by_key(|(path, _)| std::cmp::Reverse(depth(path)));
+    for (path, meta) in dirs {
+        let entries = children.remove(&path).unwrap_or_default();
+        let cid = put_dir(store, entries, meta, Default::default()).await?;
+        if path.is_empty() {
+            return Ok((cid, stats));
+        }
+        let (parent, name) = split(&path);
+        children.entry(parent).or_default().insert(name, cid);
+    }
+    unreachable!()
+}
+
+/// Write the tree rooted at `cid` to `out` as a tar archive. The entries of a root
+/// directory are written at the top level of the archive; a root file is named
+/// after its `Cid`.
+pub async fn export_tar<W, S>(store: &Arc<S>, cid: &Cid, out: &mut W) -> Result<()>
+where
+    W: Write + Send,
+    S: BlockStore + ?Sized + 'static,
+{
+    let node = get_node(&**store, cid).await?;
+    if node.is_dir() {
+        write_entries(store, &node, "", out).await?;
+    } else {
+        write_node(store, cid.to_string(), *cid, node, out).await?;
+    }
+
+    // An archive ends with two empty blocks.
+    out.write_all(&[0; 2 * BLOCK_LEN as usize])?;
+    Ok(())
+}
+
+fn write_entries<'a, W, S>(
+    store: &'a Arc<S>,
+    dir: &'a Node,
+    path: &'a str,
+    out: &'a mut W,
+) -> BoxFuture<'a, Result<()>>
+where
+    W: Write + Send,
+    S: BlockStore + ?Sized + 'static,
+{
+    async move {
+        for (name, cid) in list_dir(&**store, dir).await? {
+            ensure!(valid_name(&name), "Invalid directory entry name `{}`", name);
+            let node = get_node(&**store, &cid).await?;
+            write_node(store, join(path, &name), cid, node, out).await?;
+        }
+        Ok(())
+    }
+    .boxed()
+}
+
+fn write_node<'a, W, S>(
+    store: &'a Arc<S>,
+    path: String,
+    cid: Cid,
+    node: Node,
+    out: &'a mut W,
+) -> BoxFuture<'a, Result<()>>
+where
+    W: Write + Send,
+    S: BlockStore + ?Sized + 'static,
+{
+    async move {
+        let meta = node.metadata();
+        let mut header = Header::new_gnu();
+        header.set_mtime(meta.mtime.unwrap_or(0).max(0) as u64);
+        header.set_uid(meta.uid.unwrap_or(0).into());
+        header.set_gid(meta.gid.unwrap_or(0).into());
+        header.set_size(0);
+
+        match &node {
+            Node::File(file) => {
+                header.set_entry_type(EntryType::Regular);
+                header.set_mode(meta.mode.unwrap_or(0o644));
+                header.set_size(file.size());
+                write_header(out, header, &path, None)?;
+
+                let mut fr = FileReader::new(cid, store.clone());
+                let n = futures::io::copy(&mut fr, &mut AllowStdIo::new(&mut *out)).await?;
+                ensure!(
+                    n == file.size(),
+                    "File `{}` is {} bytes, expected {}",
+                    cid,
+                    n,
+                    file.size()
+                );
+                pad(out, n)?;
+            }
+            Node::Symlink(link) => {
+                header.set_entry_type(EntryType::Symlink);
+                header.set_mode(meta.mode.unwrap_or(0o777));
+                write_header(out, header, &path, Some(link.target()))?;
+            }
+            Node::Directory(_) | Node::ShardedDirectory(_) => {
+                header.set_entry_type(EntryType::Directory);
+                header.set_mode(meta.mode.unwrap_or(0o755));
+                write_header(out, header, &format!("{}/", path), None)?;
+                write_entries(store, &node, &path, out).await?;
+            }
+        }
+        Ok(())
+    }
+    .boxed()
+}
+
+/// Write `header` for the entry at `path`, preceded by GNU long name entries for
+/// any name that doesn't fit in the header itself.
+fn write_header<W: Write>(
+    out: &mut W,
+    mut header: Header,
+    path: &str,
+    link: Option<&str>,
+) -> Result<()> {
+    if path.len() > NAME_LEN {
+        write_long_name(out, EntryType::GNULongName, path)?;
+    }
+    set_name(&mut header.as_old_mut().name, path);
+
+    if let Some(link) = link {
+        if link.len() > NAME_LEN {
+            write_long_name(out, EntryType::GNULongLink, link)?;
+        }
+        set_name(&mut header.as_old_mut().linkname, link);
+    }
+
+    header.set_cksum();
+    out.write_all(header.as_bytes())?;
+    Ok(())
+}
+
+fn write_long_name<W: Write>(out: &mut W, ty: EntryType, name: &str) -> Result<()> {
+    let len = name.len() as u64 + 1;
+    let mut header = Header::new_gnu();
+    set_name(&mut header.as_old_mut().name, "././@LongLink");
+    header.set_entry_type(ty);
+    header.set_mode(0o644);
+    header.set_mtime(0);
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_size(len);
+    header.set_cksum();
+
+    out.write_all(header.as_bytes())?;
+    out.write_all(name.as_bytes())?;
+    out.write_all(&[0])?;
+    pad(out, len)
+}
+
+/// Copy as much of `name` as fits into the header field `field`.
+fn set_name(field: &mut [u8; NAME_LEN], name: &str) {
+    let len = name.len().min(NAME_LEN);
+    field[..len].copy_from_slice(&name.as_bytes()[..len]);
+}
+
+/// Pad an entry of `len` bytes out to a whole number of blocks.
+fn pad<W: Write>(out: &mut W, len: u64) -> Result<()> {
+    let rem = (BLOCK_LEN - len % BLOCK_LEN) % BLOCK_LEN;
+    out.write_all(&vec![0; rem as usize])?;
+    Ok(())
+}
+
+fn link_name<R: Read>(entry: &tar::Entry<R>) -> Result<String> {
+    entry
+        .link_name()?
+        .ok_or_else(|| anyhow!("Tar link entry without a target"))?
+        .to_str()
+        .map(str::to_string)
+        .ok_or_else(|| anyhow!("Non UTF-8 tar link target"))
+}
+
+/// Turn an archive path into a slash separated path relative to the archive root,
+/// rejecting any that would escape it.
+fn normalize(path: &Path) -> Result<String> {
+    let mut parts = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => {
+                let part = part
+                    .to_str()
+                    .ok_or_else(|| anyhow!("Non UTF-8 tar path {:?}", path))?;
+                ensure!(valid_name(part), "Invalid tar path {:?}", path);
+                parts.push(part);
+            }
+            Component::CurDir | Component::RootDir | Component::Prefix(_) => {}
+            Component::ParentDir => bail!("Tar path {:?} escapes the archive", path),
+        }
+    }
+    Ok(parts.join("/"))
+}
+
+/// Split `path` into its parent directory and final component.
+fn split(path: &str) -> (String, String) {
+    match path.rsplit_once('/') {
+        Some((parent, name)) => (parent.to_string(), name.to_string()),
+        None => (String::new(), path.to_string()),
+    }
+}
+
+fn depth(path: &str) -> usize {
+    if path.is_empty() {
+        0
+    } else {
+        path.matches('/').count() + 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunker::FixedChunker;
+    use crate::store::MemoryBlockStore;
+    use crate::unixfs::read_range;
+
+    fn header(path: &str, ty: EntryType, size: u64) -> Header {
+        let mut header = Header::new_gnu();
+        header.set_path(path).unwrap();
+        header.set_entry_type(ty);
+        header.set_mode(0o644);
+        header.set_size(size);
+        header
+    }
+
+    #[tokio::test]
+    async fn gnu_sparse_files_are_imported() {
+        // 4 bytes of data at offset 5000, in a 10000 byte file. GNU tar marks the end
+        // of the file with an empty region.
+        let mut header = header("sparse", EntryType::GNUSparse, 4);
+        let gnu = header.as_gnu_mut().unwrap();
+        gnu.sparse[0].set_offset(5000);
+        gnu.sparse[0].set_length(4);
+        gnu.sparse[1].set_offset(10000);
+        gnu.sparse[1].set_length(0);
+        gnu.set_real_size(10000);
+        header.set_cksum();
+        let mut tar = header.as_bytes().to_vec();
+        tar.extend_from_slice(b"data");
+        tar.resize(
+            tar.len() + BLOCK_LEN as usize - 4 + 2 * BLOCK_LEN as usize,
+            0,
+        );
+
+        let store = MemoryBlockStore::new();
+        let chunker = FixedChunker::new(1000).unwrap();
+        let (root, _) = import_tar(&tar[..], &chunker, 0, &store).await.unwrap();
+        let entries = list_dir(&store, &get_node(&store, &root).await.unwrap())
+            .await
+            .unwrap();
+        let data = read_range(&store, &entries["sparse"], 0, 20000)
+            .await
+            .unwrap();
+        let mut expected = vec![0; 10000];
+        expected[5000..5004].copy_from_slice(b"data");
+        assert_eq!(data, expected);
+    }
+
+    #[tokio::test]
+    async fn file_and_directory_at_one_path_is_an_error() {
+        let mut builder = tar::Builder::new(Vec::new());
+        for path in ["a", "a/b"] {
+            let mut header = header(path, EntryType::Regular, 1);
+            header.set_cksum();
+            builder.append(&header, &b"x"[..]).unwrap();
+        }
+        let tar = builder.into_inner().unwrap();
+
+        let store = MemoryBlockStore::new();
+        let chunker = FixedChunker::new(1000).unwrap();
+        let err = import_tar(&tar[..], &chunker, 0, &store).await.unwrap_err();
+        assert!(
+            err.to_string().contains("both a file and a directory"),
+            "{}",
+            err
+        );
+    }
+}
@@ -0,0 +1,232 @@
+use anyhow::Result;
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+
+use libipld::cid::Cid;
+
+use std::collections::BTreeSet;
+use std::fmt::{self, Display};
+
+use super::{get_children, get_node, join, list_dir, File, FileDataEntry, Node};
+use crate::store::BlockStore;
+
+/// A difference between two trees, at the `path` relative to their roots. The
+/// roots themselves have an empty path.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Change {
+    Added {
+        path: String,
+        cid: Cid,
+    },
+    Removed {
+        path: String,
+        cid: Cid,
+    },
+    /// A file whose contents changed within the half-open byte `ranges`.
+    Modified {
+        path: String,
+        from: Cid,
+        to: Cid,
+        ranges: Vec<(u64, u64)>,
+    },
+}
+
+impl Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Change::Added { path, cid } => write!(f, "A\t{}\t{}", path, cid),
+            Change::Removed { path, cid } => write!(f, "D\t{}\t{}", path, cid),
+            Change::Modified {
+                path,
+                from,
+                to,
+                ranges,
+            } => {
+                write!(f, "M\t{}\t{}\t{}", path, from, to)?;
+                for (start, end) in ranges {
+                    write!(f, "\t{}-{}", start, end)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Compare the trees rooted at `a` and `b` without fetching any file data, only the
+/// [`FileData`](super::FileData) nodes above it. Shared subtrees are skipped
+/// entirely, since equal `Cid`s mean equal contents.
+pub async fn diff<S>(store: &S, a: &Cid, b: &Cid) -> Result<Vec<Change>>
+where
+    S: BlockStore + ?Sized,
+{
+    let mut changes = Vec::new();
+    diff_at(store, String::new(), *a, *b, &mut changes).await?;
+    Ok(changes)
+}
+
+fn diff_at<'a, S>(
+    store: &'a S,
+    path: String,
+    a: Cid,
+    b: Cid,
+    out: &'a mut Vec<Change>,
+) -> BoxFuture<'a, Result<()>>
+where
+    S: BlockStore + ?Sized,
+{
+    async move {
+        if a == b {
+            return Ok(());
+        }
+
+        let (node_a, node_b) = (get_node(store, &a).await?, get_node(store, &b).await?);
+        match (&node_a, &node_b) {
+            (Node::File(file_a), Node::File(file_b)) => {
+                out.push(Change::Modified {
+                    path,
+                    from: a,
+                    to: b,
+                    ranges: diff_files(store, file_a, file_b).await?,
+                });
+            }
+            _ if !node_a.is_dir() || !node_b.is_dir() => {
+                out.push(Change::Removed {
+                    path: path.clone(),
+                    cid: a,
+                });
+                out.push(Change::Added { path, cid: b });
+            }
+            _ => {
+                let entries_a = list_dir(store, &node_a).await?;
+                let entries_b = list_dir(store, &node_b).await?;
+                let names = entries_a
+                    .keys()
+                    .chain(entries_b.keys())
+                    .collect::<BTreeSet<&String>>();
+                for name in names {
+                    let path = join(&path, name);
+                    match (entries_a.get(name), entries_b.get(name)) {
+                        (Some(a), Some(b)) => diff_at(store, path, *a, *b, out).await?,
+                        (Some(a), None) => out.push(Change::Removed { path, cid: *a }),
+                        (None, Some(b)) => out.push(Change::Added { path, cid: *b }),
+                        (None, None) => unreachable!(),
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+    .boxed()
+}
+
+/// The byte ranges that may differ between `a` and `b`: those covered by a leaf in
+/// either file that has no leaf with identical bounds and link in the other, or the
+/// whole of them if either is stored inline. Only the [`FileData`](super::FileData)
+/// nodes that differ are descended into.
+pub async fn diff_files<S>(store: &S, a: &File, b: &File) -> Result<Vec<(u64, u64)>>
+where
+    S: BlockStore + ?Sized,
+{
+    // Inline data has no entries to compare, so any change covers the whole file.
+    if a.inline.is_some() || b.inline.is_some() {
+        return Ok(if a.inline == b.inline && a.data == b.data {
+            Vec::new()
+        } else {
+            vec![(0, a.size.max(b.size))]
+        });
+    }
+
+    let mut ranges = diff_entries(store, a.data.clone(), b.data.clone()).await?;
+    ranges.sort_unstable();
+
+    let mut merged: Vec<(u64, u64)> = Vec::new();
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    Ok(merged)
+}
+
+/// The bounds of every leaf that differs between the sorted entries `a` and `b`.
+/// Entries found in both are dropped, and the nodes among the rest are replaced by
+/// their children, until only leaves are left to compare.
+fn diff_entries<S>(
+    store: &S,
+    a: Vec<FileDataEntry>,
+    b: Vec<FileDataEntry>,
+) -> BoxFuture<'_, Result<Vec<(u64, u64)>>>
+where
+    S: BlockStore + ?Sized,
+{
+    async move {
+        let only_a = a.iter().filter(|entry| !contains(&b, entry));
+        let only_b = b.iter().filter(|entry| !contains(&a, entry));
+        let (only_a, only_b): (Vec<_>, Vec<_>) = (only_a.collect(), only_b.collect());
+        if only_a
+            .iter()
+            .chain(&only_b)
+            .all(|entry| entry.node().is_none())
+        {
+            return Ok(only_a
+                .into_iter()
+                .chain(only_b)
+                .map(|entry| (entry.bounds.0, entry.bounds.1))
+                .collect());
+        }
+        let (a, b) = (expand(store, &only_a).await?, expand(store, &only_b).await?);
+        diff_entries(store, a, b).await
+    }
+    .boxed()
+}
+
+/// `data` with each [`FileData`](super::FileData) node replaced by its children.
+async fn expand<S>(store: &S, data: &[&FileDataEntry]) -> Result<Vec<FileDataEntry>>
+where
+    S: BlockStore + ?Sized,
+{
+    let mut out = Vec::with_capacity(data.len());
+    for entry in data {
+        match entry.node() {
+            Some(cid) => out.extend(get_children(store, cid, &entry.bounds).await?),
+            None => out.push((*entry).clone()),
+        }
+    }
+    Ok(out)
+}
+
+/// Whether the sorted `data` holds an entry identical to `entry`. Entries are
+/// ordered by start offset alone, so a match on the search still needs checking.
+fn contains(data: &[FileDataEntry], entry: &FileDataEntry) -> bool {
+    data.binary_search(entry).is_ok_and(|i| data[i] == *entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunker::FixedChunker;
+    use crate::store::MemoryBlockStore;
+    use crate::unixfs::{import_file, Metadata};
+
+    #[tokio::test]
+    async fn diff_descends_into_file_data() {
+        let store = MemoryBlockStore::new();
+        // Enough chunks that the root only links to FileData nodes.
+        let chunker = FixedChunker::new(16).unwrap();
+        let mut data = (0..160_000).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        let (a, _, _) = import_file(&data[..], &chunker, Metadata::default(), 0, &store)
+            .await
+            .unwrap();
+        assert!(a.data.iter().all(|entry| entry.node().is_some()));
+
+        data[100_005] ^= 1;
+        let (b, _, _) = import_file(&data[..], &chunker, Metadata::default(), 0, &store)
+            .await
+            .unwrap();
+        let ranges = diff_files(&store, &a, &b).await.unwrap();
+        assert_eq!(ranges, vec![(100_000, 100_016)]);
+        assert_eq!(diff_files(&store, &a, &a).await.unwrap(), vec![]);
+    }
+}
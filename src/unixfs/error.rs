@@ -0,0 +1,55 @@
+use libipld::cid::Cid;
+
+use thiserror::Error;
+
+/// Ways in which a [`File`](super::File) or one of the nodes under it can be malformed.
+/// Entry indices count from zero, in the node the entry belongs to, and the next
+/// entry is the one starting after it.
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum UnixfsError {
+    #[error("File data starts at byte {start} instead of 0")]
+    NotStartingAtZero { start: u64 },
+    #[error("File data entry {index} covers no bytes, from {start} to {end}")]
+    EmptyEntry { index: usize, start: u64, end: u64 },
+    #[error("File data entry {index} ends at byte {end} but the next one starts at {next}")]
+    Gap { index: usize, end: u64, next: u64 },
+    #[error("File data entry {index} ends at byte {end} past the start of the next one at {next}")]
+    Overlap { index: usize, end: u64, next: u64 },
+    #[error("File size is {size} bytes but its data ends at byte {end}")]
+    SizeMismatch { size: u64, end: u64 },
+    #[error("File has inline data as well as {entries} data entries")]
+    InlineWithEntries { entries: usize },
+    #[error("File data node `{cid}` covers bytes {start}..{end} instead of {expected_start}..{expected_end}")]
+    NodeBounds {
+        /// Boxed, as a `Cid` would make every `Result` carrying this error huge.
+        cid: Box<Cid>,
+        start: u64,
+        end: u64,
+        expected_start: u64,
+        expected_end: u64,
+    },
+}
+
+impl UnixfsError {
+    /// Replace the entry index, if any, with `f(index)`.
+    pub(super) fn map_index(self, f: impl FnOnce(usize) -> usize) -> Self {
+        match self {
+            UnixfsError::EmptyEntry { index, start, end } => UnixfsError::EmptyEntry {
+                index: f(index),
+                start,
+                end,
+            },
+            UnixfsError::Gap { index, end, next } => UnixfsError::Gap {
+                index: f(index),
+                end,
+                next,
+            },
+            UnixfsError::Overlap { index, end, next } => UnixfsError::Overlap {
+                index: f(index),
+                end,
+                next,
+            },
+            e => e,
+        }
+    }
+}
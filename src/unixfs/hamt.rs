@@ -0,0 +1,560 @@
+// https://github.com/ipfs/go-unixfs/tree/master/hamt
+//
+// A hash array mapped trie of directory entries. Keys are hashed with murmur3
+// and the hash is consumed `bit_width` bits at a time, one chunk per level, to
+// pick which of the `2^bit_width` slots of a node an entry belongs in. A slot
+// holds either a small sorted bucket of entries or a link to a child node.
+//
+// The trie is kept canonical: a bucket is only split into a child node once it
+// would exceed `bucket_size` entries, and a child node is collapsed back into
+// a bucket once it holds few enough entries, so the same set of entries always
+// produces the same root `Cid`.
+
+use anyhow::{ensure, Result};
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+
+use libipld::cid::Cid;
+use libipld::DagCbor;
+use libipld::Link;
+
+use murmur3::murmur3_x64_128;
+
+use super::{HardLinks, Metadata};
+use crate::store::{self, BlockStore};
+
+/// The default number of hash bits consumed per level, giving 256-way nodes.
+pub const DEFAULT_BIT_WIDTH: u8 = 8;
+/// The default maximum number of entries in a bucket before it is split.
+pub const DEFAULT_BUCKET_SIZE: usize = 3;
+
+/// The root of a sharded directory, with the root node stored inline.
+#[derive(Clone, DagCbor, Debug, Eq, PartialEq)]
+pub struct ShardedDirectory {
+    bit_width: u8,
+    bucket_size: u64,
+    root: NodeData,
+    #[ipld(rename = "type")]
+    ty: String,
+    #[ipld(default = Metadata::default())]
+    meta: Metadata,
+    #[ipld(default = None)]
+    hardlinks: Option<HardLinks>,
+}
+
+impl ShardedDirectory {
+    pub fn metadata(&self) -> &Metadata {
+        &self.meta
+    }
+
+    pub fn hardlinks(&self) -> Option<&HardLinks> {
+        self.hardlinks.as_ref()
+    }
+}
+
+#[derive(Clone, DagCbor, Debug, Eq, PartialEq)]
+struct NodeData {
+    /// Bit `i` is set iff slot `i` is occupied, stored least significant bit first.
+    map: Box<[u8]>,
+    /// The occupied slots, in slot order.
+    data: Vec<Element>,
+}
+
+#[derive(Clone, DagCbor, Debug, Eq, PartialEq)]
+enum Element {
+    Bucket(Vec<BucketEntry>),
+    Link(crate::Link),
+}
+
+#[derive(Clone, DagCbor, Debug, Eq, PartialEq)]
+struct BucketEntry {
+    key: String,
+    value: crate::Link,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Config {
+    bit_width: u8,
+    bucket_size: usize,
+}
+
+impl Config {
+    fn new(bit_width: u8, bucket_size: usize) -> Result<Self> {
+        ensure!(
+            (1..=8).contains(&bit_width),
+            "Invalid HAMT bit width {}",
+            bit_width
+        );
+        ensure!(bucket_size > 0, "Invalid HAMT bucket size {}", bucket_size);
+        Ok(Config {
+            bit_width,
+            bucket_size,
+        })
+    }
+
+    fn width(&self) -> usize {
+        1 << self.bit_width
+    }
+
+    /// The number of levels before the hash runs out of bits.
+    fn max_depth(&self) -> u8 {
+        64 / self.bit_width
+    }
+}
+
+enum Child {
+    /// Entries sorted by key.
+    Bucket(Vec<(String, Cid)>),
+    /// A child node that hasn't been loaded from the store yet.
+    Link(Cid),
+    Node(Box<Node>),
+}
+
+struct Node {
+    children: Vec<Option<Child>>,
+    /// The `Cid` the node was loaded from or last flushed to, `None` if it has
+    /// been modified since.
+    cid: Option<Cid>,
+}
+
+impl Node {
+    fn empty(cfg: Config) -> Self {
+        Node {
+            children: (0..cfg.width()).map(|_| None).collect(),
+            cid: None,
+        }
+    }
+
+    fn from_data(data: NodeData, cfg: Config, cid: Option<Cid>) -> Result<Self> {
+        ensure!(
+            data.map.len() == bitfield_len(cfg),
+            "Invalid HAMT bitfield length {}",
+            data.map.len()
+        );
+
+        let mut node = Node::empty(cfg);
+        node.cid = cid;
+        let mut elements = data.data.into_iter();
+        for (i, slot) in node.children.iter_mut().enumerate() {
+            if data.map[i / 8] & (1 << (i % 8)) == 0 {
+                continue;
+            }
+            *slot = match elements.next() {
+                None => None,
+                Some(Element::Bucket(entries)) => Some(Child::Bucket(
+                    entries
+                        .into_iter()
+                        .map(|entry| (entry.key, *entry.value.cid()))
+                        .collect(),
+                )),
+                Some(Element::Link(link)) => Some(Child::Link(*link.cid())),
+            };
+            ensure!(slot.is_some(), "HAMT bitfield doesn't match its data");
+        }
+        ensure!(
+            elements.next().is_none(),
+            "HAMT bitfield doesn't match its data"
+        );
+        Ok(node)
+    }
+
+    /// If this node can be replaced by a single bucket, return its entries.
+    fn collapse(&self, cfg: Config) -> Option<Vec<(String, Cid)>> {
+        let mut entries = Vec::new();
+        for child in self.children.iter().flatten() {
+            match child {
+                Child::Bucket(bucket) => entries.extend(bucket.iter().cloned()),
+                _ => return None,
+            }
+            if entries.len() > cfg.bucket_size {
+                return None;
+            }
+        }
+        entries.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        Some(entries)
+    }
+}
+
+/// A sharded map from directory entry names to `Cid`s.
+///
+/// Nodes are loaded from the `store` as they are needed, and changes are kept in
+/// memory until [`flush`](Hamt::flush) writes out the modified nodes.
+pub struct Hamt<'a, S: ?Sized> {
+    store: &'a S,
+    cfg: Config,
+    root: Node,
+    meta: Metadata,
+    hardlinks: HardLinks,
+}
+
+impl<'a, S: BlockStore + ?Sized> Hamt<'a, S> {
+    /// Create an empty HAMT consuming `bit_width` bits of hash per level.
+    pub fn new(store: &'a S, bit_width: u8, bucket_size: usize) -> Result<Self> {
+        let cfg = Config::new(bit_width, bucket_size)?;
+        Ok(Hamt {
+            store,
+            cfg,
+            root: Node::empty(cfg),
+            meta: Metadata::default(),
+            hardlinks: HardLinks::new(),
+        })
+    }
+
+    /// Open the HAMT rooted at `dir`.
+    pub fn load(store: &'a S, dir: &ShardedDirectory) -> Result<Self> {
+        let cfg = Config::new(dir.bit_width, dir.bucket_size.try_into()?)?;
+        Ok(Hamt {
+            store,
+            cfg,
+            root: Node::from_data(dir.root.clone(), cfg, None)?,
+            meta: dir.meta.clone(),
+            hardlinks: dir.hardlinks.clone().unwrap_or_default(),
+        })
+    }
+
+    /// Set the metadata recorded on the root by the next [`flush`](Hamt::flush).
+    pub fn set_metadata(&mut self, meta: Metadata) {
+        self.meta = meta;
+    }
+
+    /// Set the hard link groups recorded on the root by the next [`flush`](Hamt::flush).
+    pub fn set_hardlinks(&mut self, hardlinks: HardLinks) {
+        self.hardlinks = hardlinks;
+    }
+
+    // Directories are always read whole with `entries`, so only tests look up single keys.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub async fn get(&mut self, key: &str) -> Result<Option<Cid>> {
+        get(&mut self.root, self.store, self.cfg, hash_key(key)?, 0, key).await
+    }
+
+    /// Insert `key`, returning the value it previously mapped to, if any.
+    pub async fn insert(&mut self, key: String, value: Cid) -> Result<Option<Cid>> {
+        let hash = hash_key(&key)?;
+        insert(&mut self.root, self.store, self.cfg, hash, 0, key, value).await
+    }
+
+    /// Remove `key`, returning the value it mapped to, if any.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub async fn remove(&mut self, key: &str) -> Result<Option<Cid>> {
+        remove(&mut self.root, self.store, self.cfg, hash_key(key)?, 0, key).await
+    }
+
+    /// Every entry in the HAMT, in hash order.
+    pub async fn entries(&mut self) -> Result<Vec<(String, Cid)>> {
+        let mut entries = Vec::new();
+        collect(&mut self.root, self.store, self.cfg, &mut entries).await?;
+        Ok(entries)
+    }
+
+    /// Write every modified node to the store, returning the new root.
+    pub async fn flush(&mut self) -> Result<(ShardedDirectory, Cid)> {
+        let dir = ShardedDirectory {
+            bit_width: self.cfg.bit_width,
+            bucket_size: self.cfg.bucket_size.try_into()?,
+            root: flush(&mut self.root, self.store, self.cfg).await?,
+            ty: super::HAMT_TYPE.to_string(),
+            meta: self.meta.clone(),
+            hardlinks: Some(self.hardlinks.clone()).filter(|links| !links.is_empty()),
+        };
+        let cid = store::put_node(self.store, &dir).await?;
+        Ok((dir, cid))
+    }
+}
+
+/// Replace `slot` with the node it links to, if it is an unloaded link.
+async fn load<S: BlockStore + ?Sized>(
+    slot: &mut Option<Child>,
+    store: &S,
+    cfg: Config,
+) -> Result<()> {
+    if let Some(Child::Link(cid)) = slot {
+        let cid = *cid;
+        let data: NodeData = store::get_node(store, &cid).await?;
+        *slot = Some(Child::Node(Box::new(Node::from_data(
+            data,
+            cfg,
+            Some(cid),
+        )?)));
+    }
+    Ok(())
+}
+
+fn get<'b, S: BlockStore + ?Sized>(
+    node: &'b mut Node,
+    store: &'b S,
+    cfg: Config,
+    hash: u64,
+    depth: u8,
+    key: &'b str,
+) -> BoxFuture<'b, Result<Option<Cid>>> {
+    async move {
+        let slot = &mut node.children[split_hash(hash, cfg.bit_width, depth)?];
+        load(slot, store, cfg).await?;
+        match slot {
+            None => Ok(None),
+            Some(Child::Bucket(entries)) => Ok(entries
+                .binary_search_by(|(k, _)| k.as_str().cmp(key))
+                .ok()
+                .map(|i| entries[i].1)),
+            Some(Child::Node(child)) => get(child, store, cfg, hash, depth + 1, key).await,
+            Some(Child::Link(_)) => unreachable!(),
+        }
+    }
+    .boxed()
+}
+
+fn insert<'b, S: BlockStore + ?Sized>(
+    node: &'b mut Node,
+    store: &'b S,
+    cfg: Config,
+    hash: u64,
+    depth: u8,
+    key: String,
+    value: Cid,
+) -> BoxFuture<'b, Result<Option<Cid>>> {
+    async move {
+        node.cid = None;
+        let slot = &mut node.children[split_hash(hash, cfg.bit_width, depth)?];
+        load(slot, store, cfg).await?;
+        if slot.is_none() {
+            *slot = Some(Child::Bucket(vec![(key, value)]));
+            return Ok(None);
+        }
+
+        match slot.as_mut().unwrap() {
+            Child::Bucket(entries) => {
+                match entries.binary_search_by(|(k, _)| k.cmp(&key)) {
+                    Ok(i) => Ok(Some(std::mem::replace(&mut entries[i].1, value))),
+                    // Once the hash is exhausted there is nowhere left to split to.
+                    Err(i) if entries.len() < cfg.bucket_size || depth + 1 >= cfg.max_depth() => {
+                        entries.insert(i, (key, value));
+                        Ok(None)
+                    }
+                    Err(_) => {
+                        let mut child = Node::empty(cfg);
+                        let entries = std::mem::take(entries);
+                        for (k, v) in entries.into_iter().chain(std::iter::once((key, value))) {
+                            let hash = hash_key(&k)?;
+                            insert(&mut child, store, cfg, hash, depth + 1, k, v).await?;
+                        }
+                        *slot = Some(Child::Node(Box::new(child)));
+                        Ok(None)
+                    }
+                }
+            }
+            Child::Node(child) => insert(child, store, cfg, hash, depth + 1, key, value).await,
+            Child::Link(_) => unreachable!(),
+        }
+    }
+    .boxed()
+}
+
+fn remove<'b, S: BlockStore + ?Sized>(
+    node: &'b mut Node,
+    store: &'b S,
+    cfg: Config,
+    hash: u64,
+    depth: u8,
+    key: &'b str,
+) -> BoxFuture<'b, Result<Option<Cid>>> {
+    async move {
+        let slot = &mut node.children[split_hash(hash, cfg.bit_width, depth)?];
+        load(slot, store, cfg).await?;
+        let removed = match slot {
+            None => None,
+            Some(Child::Bucket(entries)) => {
+                match entries.binary_search_by(|(k, _)| k.as_str().cmp(key)) {
+                    Err(_) => None,
+                    Ok(i) => {
+                        let (_, value) = entries.remove(i);
+                        if entries.is_empty() {
+                            *slot = None;
+                        }
+                        Some(value)
+                    }
+                }
+            }
+            Some(Child::Node(child)) => {
+                let removed = remove(child, store, cfg, hash, depth + 1, key).await?;
+                if removed.is_some() {
+                    if let Some(entries) = child.collapse(cfg) {
+                        *slot = if entries.is_empty() {
+                            None
+                        } else {
+                            Some(Child::Bucket(entries))
+                        };
+                    }
+                }
+                removed
+            }
+            Some(Child::Link(_)) => unreachable!(),
+        };
+
+        if removed.is_some() {
+            node.cid = None;
+        }
+        Ok(removed)
+    }
+    .boxed()
+}
+
+fn collect<'b, S: BlockStore + ?Sized>(
+    node: &'b mut Node,
+    store: &'b S,
+    cfg: Config,
+    out: &'b mut Vec<(String, Cid)>,
+) -> BoxFuture<'b, Result<()>> {
+    async move {
+        for slot in node.children.iter_mut() {
+            load(slot, store, cfg).await?;
+            match slot {
+                Some(Child::Bucket(entries)) => out.extend(entries.iter().cloned()),
+                Some(Child::Node(child)) => collect(child, store, cfg, out).await?,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+    .boxed()
+}
+
+/// Store every modified descendant of `node`, returning `node`'s own encoding.
+fn flush<'b, S: BlockStore + ?Sized>(
+    node: &'b mut Node,
+    store: &'b S,
+    cfg: Config,
+) -> BoxFuture<'b, Result<NodeData>> {
+    async move {
+        let mut map = vec![0_u8; bitfield_len(cfg)];
+        let mut data = Vec::new();
+        for (i, slot) in node.children.iter_mut().enumerate() {
+            let element = match slot {
+                None => continue,
+                Some(Child::Bucket(entries)) => Element::Bucket(
+                    entries
+                        .iter()
+                        .map(|(key, value)| BucketEntry {
+                            key: key.clone(),
+                            value: Link::new(*value),
+                        })
+                        .collect(),
+                ),
+                Some(Child::Link(cid)) => Element::Link(Link::new(*cid)),
+                Some(Child::Node(child)) => {
+                    let cid = match child.cid {
+                        Some(cid) => cid,
+                        None => {
+                            let data = flush(child, store, cfg).await?;
+                            let cid = store::put_node(store, &data).await?;
+                            child.cid = Some(cid);
+                            cid
+                        }
+                    };
+                    Element::Link(Link::new(cid))
+                }
+            };
+            map[i / 8] |= 1 << (i % 8);
+            data.push(element);
+        }
+        Ok(NodeData {
+            map: map.into_boxed_slice(),
+            data,
+        })
+    }
+    .boxed()
+}
+
+fn bitfield_len(cfg: Config) -> usize {
+    (cfg.width() / 8).max(1)
+}
+
+/// The `offset`th chunk of `n` bits of `hash`, most significant first.
+fn split_hash(hash: u64, n: u8, offset: u8) -> Result<usize> {
+    ensure!((1..=8).contains(&n), "Invalid HAMT bit width {}", n);
+    ensure!(offset < 64 / n, "HAMT hash exhausted at depth {}", offset);
+
+    let shift = 64 - u32::from(n) * (u32::from(offset) + 1);
+    Ok(((hash >> shift) & ((1 << n) - 1)) as usize)
+}
+
+fn compute_hash<T>(read: &mut T) -> Result<u64>
+where
+    T: std::io::Read,
+{
+    let hash = murmur3_x64_128(read, 0)?;
+    let buf16: [u8; 16] = hash.to_be_bytes();
+    let buf8: [u8; 8] = buf16[0..8].try_into()?;
+    Ok(u64::from_be_bytes(buf8))
+}
+
+fn hash_key(key: &str) -> Result<u64> {
+    compute_hash(&mut std::io::Cursor::new(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{cid_for, MemoryBlockStore, RAW};
+
+    fn entry(i: usize) -> (String, Cid) {
+        (format!("file-{}", i), cid_for(RAW, &i.to_be_bytes()))
+    }
+
+    /// A HAMT of `n` entries with small nodes and buckets, so it is several levels deep.
+    async fn build(store: &MemoryBlockStore, n: usize) -> Hamt<'_, MemoryBlockStore> {
+        let mut hamt = Hamt::new(store, 4, 2).unwrap();
+        for i in 0..n {
+            let (key, value) = entry(i);
+            assert_eq!(hamt.insert(key, value).await.unwrap(), None);
+        }
+        hamt
+    }
+
+    #[tokio::test]
+    async fn get_after_many_inserts() {
+        let store = MemoryBlockStore::new();
+        let mut hamt = build(&store, 2000).await;
+        for i in 0..2000 {
+            let (key, value) = entry(i);
+            assert_eq!(hamt.get(&key).await.unwrap(), Some(value));
+        }
+        assert_eq!(hamt.get("missing").await.unwrap(), None);
+
+        // And again with every node loaded back from the store.
+        let (dir, _) = hamt.flush().await.unwrap();
+        let mut hamt = Hamt::load(&store, &dir).unwrap();
+        for i in (0..2000).step_by(7) {
+            let (key, value) = entry(i);
+            assert_eq!(hamt.get(&key).await.unwrap(), Some(value));
+        }
+        assert_eq!(hamt.entries().await.unwrap().len(), 2000);
+    }
+
+    #[tokio::test]
+    async fn remove_restores_canonical_root() {
+        let store = MemoryBlockStore::new();
+        let mut hamt = build(&store, 500).await;
+        let (dir, cid) = hamt.flush().await.unwrap();
+
+        let mut hamt = Hamt::load(&store, &dir).unwrap();
+        for i in 500..1500 {
+            let (key, value) = entry(i);
+            hamt.insert(key, value).await.unwrap();
+        }
+        for i in 500..1500 {
+            let (key, value) = entry(i);
+            assert_eq!(hamt.remove(&key).await.unwrap(), Some(value));
+        }
+        assert_eq!(hamt.remove("missing").await.unwrap(), None);
+        assert_eq!(hamt.flush().await.unwrap().1, cid);
+
+        // Removing everything leaves the same root as a new, empty HAMT.
+        for i in 0..500 {
+            hamt.remove(&entry(i).0).await.unwrap();
+        }
+        let (_, empty) = Hamt::new(&store, 4, 2).unwrap().flush().await.unwrap();
+        assert_eq!(hamt.flush().await.unwrap().1, empty);
+    }
+}
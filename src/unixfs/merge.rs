@@ -0,0 +1,403 @@
+use anyhow::{anyhow, Result};
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+
+use libipld::cid::Cid;
+
+use serde_json::{json, Value};
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::{get_node, join, list_dir, put_dir, HardLinks, Metadata};
+use crate::store::BlockStore;
+
+/// A path that was changed differently on both sides of a merge. `None` means the
+/// path didn't exist on that side. The merged tree keeps `ours` at this path.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Conflict {
+    pub path: String,
+    pub base: Option<Cid>,
+    pub ours: Option<Cid>,
+    pub theirs: Option<Cid>,
+}
+
+impl Conflict {
+    pub fn to_json(&self) -> Value {
+        let cid = |cid: &Option<Cid>| cid.map(|cid| cid.to_string());
+        json!({
+            "path": self.path,
+            "base": cid(&self.base),
+            "ours": cid(&self.ours),
+            "theirs": cid(&self.theirs),
+        })
+    }
+}
+
+/// Three-way merge the trees `ours` and `theirs` against their common ancestor
+/// `base`, returning the merged root along with any conflicts.
+///
+/// A path changed on only one side takes that side's version. Directories changed
+/// on both sides are merged entry by entry; anything else changed on both sides
+/// is a conflict.
+///
+/// Merged directories keep the hard link groups of their entries, with those from
+/// `theirs` renumbered past every group in `ours` so the two can't be confused.
+/// Subtrees taken whole from either side keep their groups as they are.
+pub async fn merge<S>(
+    store: &S,
+    base: &Cid,
+    ours: &Cid,
+    theirs: &Cid,
+) -> Result<(Cid, Vec<Conflict>)>
+where
+    S: BlockStore + ?Sized,
+{
+    let mut conflicts = Vec::new();
+    let mut offset = Offset {
+        ours: *ours,
+        value: None,
+    };
+    let root = merge_at(
+        store,
+        String::new(),
+        Some(*base),
+        Some(*ours),
+        Some(*theirs),
+        &mut offset,
+        &mut conflicts,
+    )
+    .await?
+    .ok_or_else(|| anyhow!("Merge removed the root"))?;
+    Ok((root, conflicts))
+}
+
+fn merge_at<'a, S>(
+    store: &'a S,
+    path: String,
+    base: Option<Cid>,
+    ours: Option<Cid>,
+    theirs: Option<Cid>,
+    offset: &'a mut Offset,
+    conflicts: &'a mut Vec<Conflict>,
+) -> BoxFuture<'a, Result<Option<Cid>>>
+where
+    S: BlockStore + ?Sized,
+{
+    async move {
+        if ours == theirs || base == theirs {
+            return Ok(ours);
+        }
+        if base == ours {
+            return Ok(theirs);
+        }
+
+        // Both sides changed; only directories on both sides can be merged further.
+        let (dir_ours, dir_theirs) = match (ours, theirs) {
+            (Some(o), Some(t)) => (dir_entries(store, o).await?, dir_entries(store, t).await?),
+            _ => (None, None),
+        };
+        let ((entries_ours, meta_ours, links_ours), (entries_theirs, meta_theirs, links_theirs)) =
+            match (dir_ours, dir_theirs) {
+                (Some(o), Some(t)) => (o, t),
+                _ => {
+                    conflicts.push(Conflict {
+                        path,
+                        base,
+                        ours,
+                        theirs,
+                    });
+                    return Ok(ours);
+                }
+            };
+        let (entries_base, meta_base) = match base {
+            None => (BTreeMap::new(), None),
+            Some(base) => match dir_entries(store, base).await? {
+                None => (BTreeMap::new(), None),
+                Some((entries, meta, _)) => (entries, Some(meta)),
+            },
+        };
+        let meta = if meta_base.as_ref() == Some(&meta_ours) {
+            meta_theirs
+        } else {
+            meta_ours
+        };
+
+        let names = entries_base
+            .keys()
+            .chain(entries_ours.keys())
+            .chain(entries_theirs.keys())
+            .cloned()
+            .collect::<BTreeSet<String>>();
+        let mut merged = BTreeMap::new();
+        let mut hardlinks = HardLinks::new();
+        for name in names {
+            let (ours, theirs) = (entries_ours.get(&name), entries_theirs.get(&name));
+            let cid = merge_at(
+                store,
+                join(&path, &name),
+                entries_base.get(&name).copied(),
+                ours.copied(),
+                theirs.copied(),
+                offset,
+                conflicts,
+            )
+            .await?;
+            let cid = match cid {
+                None => continue,
+                Some(cid) => cid,
+            };
+            // Entries merged from both sides are directories, which aren't linked.
+            let group = if Some(&cid) == ours {
+                links_ours.get(&name).copied()
+            } else if let (true, Some(&group)) = (Some(&cid) == theirs, links_theirs.get(&name)) {
+                Some(offset.renumber(store, group).await?)
+            } else {
+                None
+            };
+            if let Some(group) = group {
+                hardlinks.insert(name.clone(), group);
+            }
+            merged.insert(name, cid);
+        }
+        Ok(Some(put_dir(store, merged, meta, hardlinks).await?))
+    }
+    .boxed()
+}
+
+/// The entries, metadata and hard link groups of the node `cid` if it is a
+/// directory, `None` otherwise.
+async fn dir_entries<S>(
+    store: &S,
+    cid: Cid,
+) -> Result<Option<(BTreeMap<String, Cid>, Metadata, HardLinks)>>
+where
+    S: BlockStore + ?Sized,
+{
+    let node = get_node(store, &cid).await?;
+    if !node.is_dir() {
+        return Ok(None);
+    }
+    Ok(Some((
+        list_dir(store, &node).await?,
+        node.metadata().clone(),
+        node.hardlinks().cloned().unwrap_or_default(),
+    )))
+}
+
+/// How far to move the hard link groups of `theirs`, to be clear of every group in
+/// the tree `ours`. Finding that means walking all of `ours`, so it is only done
+/// once a group from `theirs` turns up.
+struct Offset {
+    ours: Cid,
+    value: Option<u64>,
+}
+
+impl Offset {
+    async fn renumber<S>(&mut self, store: &S, group: u64) -> Result<u64>
+    where
+        S: BlockStore + ?Sized,
+    {
+        let offset = match self.value {
+            Some(offset) => offset,
+            None => {
+                let offset = max_group(store, self.ours).await?.map_or(0, |max| max + 1);
+                *self.value.insert(offset)
+            }
+        };
+        group
+            .checked_add(offset)
+            .ok_or_else(|| anyhow!("Too many hard link groups to merge"))
+    }
+}
+
+/// The largest hard link group anywhere in the tree `cid`, if there are any.
+fn max_group<S>(store: &S, cid: Cid) -> BoxFuture<'_, Result<Option<u64>>>
+where
+    S: BlockStore + ?Sized,
+{
+    async move {
+        let node = get_node(store, &cid).await?;
+        if !node.is_dir() {
+            return Ok(None);
+        }
+        let mut max = node
+            .hardlinks()
+            .and_then(|links| links.values().max().copied());
+        for cid in list_dir(store, &node).await?.into_values() {
+            max = max.max(max_group(store, cid).await?);
+        }
+        Ok(max)
+    }
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunker::FixedChunker;
+    use crate::store::MemoryBlockStore;
+    use crate::unixfs::{export, import_dir, import_file, ExportOptions, ImportOptions};
+
+    use std::os::unix::fs::MetadataExt;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    /// Write `data` to `dir/name` with a fixed mtime and hard link it as `dir/link`.
+    fn linked_pair(dir: &Path, name: &str, link: &str, data: &[u8]) {
+        std::fs::create_dir_all(dir).unwrap();
+        std::fs::write(dir.join(name), data).unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(dir.join(name))
+            .unwrap()
+            .set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000))
+            .unwrap();
+        std::fs::hard_link(dir.join(name), dir.join(link)).unwrap();
+    }
+
+    async fn file(store: &MemoryBlockStore, data: &str) -> Cid {
+        let chunker = FixedChunker::new(1000).unwrap();
+        import_file(data.as_bytes(), &chunker, Metadata::default(), 1024, store)
+            .await
+            .unwrap()
+            .1
+    }
+
+    async fn dir(store: &MemoryBlockStore, entries: &[(&str, Cid)]) -> Cid {
+        let entries = entries
+            .iter()
+            .map(|(name, cid)| (name.to_string(), *cid))
+            .collect();
+        put_dir(store, entries, Metadata::default(), HardLinks::new())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn add_on_one_side_and_remove_on_the_other() {
+        let store = MemoryBlockStore::new();
+        let (a, b, c) = (
+            file(&store, "a").await,
+            file(&store, "b").await,
+            file(&store, "c").await,
+        );
+        let base = dir(&store, &[("a", a), ("b", b)]).await;
+        let ours = dir(&store, &[("a", a), ("b", b), ("c", c)]).await;
+        let theirs = dir(&store, &[("a", a)]).await;
+
+        let (merged, conflicts) = merge(&store, &base, &ours, &theirs).await.unwrap();
+        assert!(conflicts.is_empty());
+        assert_eq!(merged, dir(&store, &[("a", a), ("c", c)]).await);
+    }
+
+    #[tokio::test]
+    async fn changing_a_file_on_both_sides_conflicts() {
+        let store = MemoryBlockStore::new();
+        let (v1, v2, v3) = (
+            file(&store, "1").await,
+            file(&store, "2").await,
+            file(&store, "3").await,
+        );
+        let base = dir(&store, &[("f", v1)]).await;
+        let ours = dir(&store, &[("f", v2)]).await;
+        let theirs = dir(&store, &[("f", v3)]).await;
+
+        let (merged, conflicts) = merge(&store, &base, &ours, &theirs).await.unwrap();
+        assert_eq!(merged, ours);
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(
+            conflicts[0].to_json(),
+            json!({
+                "path": "f",
+                "base": v1.to_string(),
+                "ours": v2.to_string(),
+                "theirs": v3.to_string(),
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn deleting_on_both_sides() {
+        let store = MemoryBlockStore::new();
+        let (a, b, c, d) = (
+            file(&store, "a").await,
+            file(&store, "b").await,
+            file(&store, "c").await,
+            file(&store, "d").await,
+        );
+        let base = dir(&store, &[("a", a), ("b", b)]).await;
+        // The sides differ elsewhere, so the directory really is merged.
+        let ours = dir(&store, &[("b", b), ("c", c)]).await;
+        let theirs = dir(&store, &[("b", b), ("d", d)]).await;
+
+        let (merged, conflicts) = merge(&store, &base, &ours, &theirs).await.unwrap();
+        assert!(conflicts.is_empty());
+        assert_eq!(merged, dir(&store, &[("b", b), ("c", c), ("d", d)]).await);
+    }
+
+    #[tokio::test]
+    async fn directories_changed_on_both_sides_are_merged() {
+        let store = MemoryBlockStore::new();
+        let (x, y) = (file(&store, "x").await, file(&store, "y").await);
+        let (x2, y2) = (file(&store, "x2").await, file(&store, "y2").await);
+        let base = dir(&store, &[("d", dir(&store, &[("x", x), ("y", y)]).await)]).await;
+        let ours = dir(&store, &[("d", dir(&store, &[("x", x2), ("y", y)]).await)]).await;
+        let theirs = dir(&store, &[("d", dir(&store, &[("x", x), ("y", y2)]).await)]).await;
+
+        let (merged, conflicts) = merge(&store, &base, &ours, &theirs).await.unwrap();
+        assert!(conflicts.is_empty());
+        let expected = dir(&store, &[("d", dir(&store, &[("x", x2), ("y", y2)]).await)]).await;
+        assert_eq!(merged, expected);
+    }
+
+    #[tokio::test]
+    async fn merged_hard_links_stay_apart() {
+        let root = std::env::temp_dir().join(format!("tops-merge-links-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        // Both imports number their groups from 0, and `a` and `c` are the same node.
+        linked_pair(&root.join("ours"), "a", "b", b"same");
+        linked_pair(&root.join("ours/x"), "e", "f", b"one");
+        linked_pair(&root.join("theirs"), "c", "d", b"same");
+        linked_pair(&root.join("theirs/y"), "g", "h", b"two");
+
+        let store = Arc::new(MemoryBlockStore::new());
+        let chunker = FixedChunker::new(1000).unwrap();
+        let opts = ImportOptions::default();
+        let (ours, _) = import_dir(&root.join("ours"), &chunker, &opts, &*store)
+            .await
+            .unwrap();
+        let (theirs, _) = import_dir(&root.join("theirs"), &chunker, &opts, &*store)
+            .await
+            .unwrap();
+        let base = put_dir(
+            &*store,
+            BTreeMap::new(),
+            Metadata::default(),
+            HardLinks::new(),
+        )
+        .await
+        .unwrap();
+        let (merged, conflicts) = merge(&*store, &base, &ours, &theirs).await.unwrap();
+        assert!(conflicts.is_empty());
+
+        let out = root.join("out");
+        let opts = ExportOptions {
+            owner: false,
+            hardlinks: true,
+            ..ExportOptions::default()
+        };
+        export(&store, &merged, &out, &opts).await.unwrap();
+        let ino = |name| std::fs::metadata(out.join(name)).unwrap().ino();
+        let inodes = ["a", "b", "c", "d", "x/e", "x/f", "y/g", "y/h"].map(ino);
+        let contents = std::fs::read(out.join("y/g")).unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+        assert_eq!(inodes[0], inodes[1]);
+        assert_eq!(inodes[2], inodes[3]);
+        assert_ne!(inodes[0], inodes[2]);
+        assert_eq!(inodes[4], inodes[5]);
+        assert_eq!(inodes[6], inodes[7]);
+        assert_ne!(inodes[4], inodes[6]);
+        assert_eq!(contents, b"two");
+    }
+}